@@ -0,0 +1,130 @@
+// Parser throughput on a synthetic feed. The feed is generated once into the temp
+// directory; its size in MB comes from XMLPOC_BENCH_MB (default 256), e.g.
+//   XMLPOC_BENCH_MB=4096 cargo bench --bench parse
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use quick_xml::Reader;
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use xmlpoc::{config, normalized, parallel, parser};
+
+const TRANSACTION: &str = r#"  <Transaction>
+    <Event><EntityStatus>ISSUED</EntityStatus></Event>
+    <Document DateOfIssuance="2025-11-20" ValidatingCarrier="LH">
+      <IssuanceDetails CityPOS="FRA" Iata="12345678" OfficeId="FRALH0001"/>
+      <PricingDetails><CurrencyOfPayment>EUR</CurrencyOfPayment><TourCode>TC1</TourCode><RevenueAttributableAgent AgencyNumber="12345678"/></PricingDetails>
+      <BookingInformation><PNRIdentification><AmadeusRecordLocator><ID>ABC123</ID></AmadeusRecordLocator></PNRIdentification></BookingInformation>
+      <Fares>
+        <Fare FareDescription="NET"><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="100.00"/><ROE>1.0</ROE></Amount></AccountableEntity></Fare>
+        <Fare FareDescription="PUBLISHED"><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="120.00"/><ROE>1.0</ROE></Amount></AccountableEntity></Fare>
+        <Fare FareDescription="SELLING"><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="130.00"/></Amount></AccountableEntity></Fare>
+      </Fares>
+      <StandardCommission><Commission><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="5.00"/></Amount></AccountableEntity></Commission></StandardCommission>
+      <Coupon DocumentNbr="{TICKET}" ConjunctiveDocumentNbr="{TICKET}" Number="1" Status="F">
+        <SegmentInfo OriginAirportCode="FRA" DestinationAirportCode="JFK" DepartureDate="2025-12-01T10:30:00" ArrivalDate="2025-12-01T13:15:00">
+          <CompanyDetails><MarketingCarrier>LH</MarketingCarrier><OperatingCarrier>LH</OperatingCarrier></CompanyDetails>
+          <ClassDetails><BookingClass>Y</BookingClass><OperatingCabinClass>M</OperatingCabinClass></ClassDetails>
+          <FlightIdentification><OperatingFlightNumber><FlightNumber>400</FlightNumber></OperatingFlightNumber></FlightIdentification>
+        </SegmentInfo>
+        <CouponDetails><FareBasisCode>YLOW</FareBasisCode></CouponDetails>
+        <CalculatedAmounts>
+          <CouponProratedFare><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="60.00"/></Amount></AccountableEntity></CouponProratedFare>
+          <CouponTaxes><CollectedTaxesCpnLvl>
+            <Tax NatureCode="AC" ISOCode="YQ" IsRefundable="N"><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="20.00"/></Amount></AccountableEntity></Tax>
+            <Tax NatureCode="GV" ISOCode="YR" IsRefundable="Y"><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="7.50"/></Amount></AccountableEntity></Tax>
+          </CollectedTaxesCpnLvl></CouponTaxes>
+          <CouponStandardCommission><Commission><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="3.00"/></Amount></AccountableEntity></Commission></CouponStandardCommission>
+        </CalculatedAmounts>
+      </Coupon>
+      <Coupon DocumentNbr="{TICKET}" ConjunctiveDocumentNbr="{TICKET}" Number="2" Status="O">
+        <SegmentInfo OriginAirportCode="JFK" DestinationAirportCode="FRA" DepartureDate="2025-12-10T18:00:00" ArrivalDate="2025-12-11T08:05:00"/>
+        <CalculatedAmounts>
+          <CouponProratedFare><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="40.00"/></Amount></AccountableEntity></CouponProratedFare>
+          <CouponTaxes><CollectedTaxesCpnLvl>
+            <Tax NatureCode="AC" ISOCode="YQ" IsRefundable="N"><AccountableEntity><Amount><AmountType>ACCOUNTED</AmountType><Amount Amount="15.00"/></Amount></AccountableEntity></Tax>
+          </CollectedTaxesCpnLvl></CouponTaxes>
+        </CalculatedAmounts>
+      </Coupon>
+    </Document>
+  </Transaction>
+"#;
+
+// Writes the synthetic feed unless a file of that size is already there
+fn synthetic_feed() -> (PathBuf, u64) {
+    let mb: u64 = std::env::var("XMLPOC_BENCH_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(256);
+    let path = std::env::temp_dir().join(format!("xmlpoc_bench_{}MB.xml", mb));
+    let target = mb * 1024 * 1024;
+
+    if let Ok(meta) = std::fs::metadata(&path)
+        && meta.len() >= target
+    {
+        return (path, meta.len());
+    }
+
+    let mut out = BufWriter::new(File::create(&path).expect("create bench feed"));
+    let mut written = 0u64;
+    let header = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<AMA_REV.Feed Version=\"1.0\">\n";
+    out.write_all(header.as_bytes()).unwrap();
+    written += header.len() as u64;
+
+    let mut ticket = 2200000000000u64;
+    while written < target {
+        let trx = TRANSACTION.replace("{TICKET}", &ticket.to_string());
+        out.write_all(trx.as_bytes()).unwrap();
+        written += trx.len() as u64;
+        ticket += 1;
+    }
+    out.write_all(b"</AMA_REV.Feed>\n").unwrap();
+    out.flush().unwrap();
+
+    let len = std::fs::metadata(&path).unwrap().len();
+    (path, len)
+}
+
+fn reader(path: &PathBuf) -> Reader<BufReader<File>> {
+    let file = File::open(path).expect("open bench feed");
+    let mut reader = Reader::from_reader(BufReader::with_capacity(1 << 20, file));
+    reader.trim_text(true);
+    reader
+}
+
+fn bench_parsers(c: &mut Criterion) {
+    let (path, len) = synthetic_feed();
+    let options = parser::ParseOptions::default();
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(len));
+    group.sample_size(10);
+
+    group.bench_function("parse_xml", |b| {
+        b.iter(|| {
+            let feed = parser::parse_xml_with_options(&mut reader(&path), &options).unwrap();
+            black_box(feed.records.len())
+        })
+    });
+
+    // the parallel parser works on the whole file in memory, as process_key does
+    let bytes = std::fs::read(&path).expect("read bench feed");
+    group.bench_function("parse_xml_parallel", |b| {
+        b.iter(|| {
+            let feed = parallel::parse_xml_parallel(&bytes, &options, config::PARALLEL_CHUNK_BYTES).unwrap();
+            black_box(feed.records.len())
+        })
+    });
+    drop(bytes);
+
+    group.bench_function("parse_normalized", |b| {
+        b.iter(|| {
+            let mut keys = normalized::SurrogateKeys::default();
+            let feed = normalized::parse_normalized(&mut reader(&path), "bench.xml", &mut keys, &options).unwrap();
+            black_box(feed.coupons.len())
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsers);
+criterion_main!(benches);
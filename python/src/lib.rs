@@ -0,0 +1,151 @@
+//! Python bindings of the feed parser, built with maturin as the `xmlpoc` module.
+//! Records come out exactly as the Rust `parse_xml` produces them, with the
+//! default ParseOptions of config.rs.
+
+use std::sync::Mutex;
+
+use arrow::pyarrow::ToPyArrow;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use quick_xml::Reader;
+use xmlpoc::arrowbatch::{self, RecordBatches};
+use xmlpoc::{config, diagnostics};
+use xmlpoc::{ParseOptions, Record, RecordStream, parse_xml_with_options};
+
+create_exception!(xmlpoc, ParseError, PyException, "A feed file could not be read or parsed.");
+
+fn to_py_err(e: anyhow::Error) -> PyErr {
+    // alternate form keeps the context chain, e.g. the file path and the element path
+    ParseError::new_err(format!("{:#}", e))
+}
+
+fn parse_records(path: &str) -> anyhow::Result<Vec<Record>> {
+    let data = std::fs::read(path).map_err(|e| anyhow::anyhow!("reading {}: {}", path, e))?;
+    let mut reader = Reader::from_reader(data.as_slice());
+    reader.trim_text(true);
+    parse_xml_with_options(&mut reader, &ParseOptions::default())
+        .map(|feed| feed.records)
+        .map_err(|e| diagnostics::locate(e, path, &data))
+}
+
+fn record_dict<'py>(py: Python<'py>, rec: &Record) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    for column in Record::COLUMNS {
+        dict.set_item(column, rec.field(column).unwrap_or_default())?;
+    }
+    Ok(dict)
+}
+
+/// parse_file(path) -> list[dict[str, str]]
+///
+/// Parses a whole feed file; one dict per record, keyed by column name.
+#[pyfunction]
+fn parse_file<'py>(py: Python<'py>, path: &str) -> PyResult<Vec<Bound<'py, PyDict>>> {
+    let records = py.detach(|| parse_records(path)).map_err(to_py_err)?;
+    records.iter().map(|rec| record_dict(py, rec)).collect()
+}
+
+/// iter_file(path) -> Iterator[dict[str, str]]
+///
+/// Yields the records of a feed file while it is being parsed, holding only a
+/// bounded number of them in memory.
+#[pyfunction]
+fn iter_file(path: &str) -> PyResult<RecordIterator> {
+    let stream = RecordStream::open(path, ParseOptions::default()).map_err(to_py_err)?;
+    Ok(RecordIterator { stream: Mutex::new(stream) })
+}
+
+/// parse_file_arrow(path) -> pyarrow.RecordBatch
+///
+/// Parses a whole feed file into one RecordBatch with the typed record schema.
+/// The parsed values are copied into the Arrow arrays once; only the handoff of the
+/// finished buffers to pyarrow, through the Arrow C data interface, is zero-copy.
+#[pyfunction]
+fn parse_file_arrow(py: Python<'_>, path: &str) -> PyResult<Py<PyAny>> {
+    let batch = py
+        .detach(|| parse_records(path).and_then(|records| arrowbatch::to_record_batch(&records)))
+        .map_err(to_py_err)?;
+    Ok(batch.to_pyarrow(py)?.unbind())
+}
+
+/// iter_file_arrow(path, batch_size=None) -> Iterator[pyarrow.RecordBatch]
+///
+/// Yields RecordBatches of at most batch_size rows (default ARROW_BATCH_SIZE of
+/// config.rs) while the file is being parsed.
+#[pyfunction]
+#[pyo3(signature = (path, batch_size=None))]
+fn iter_file_arrow(path: &str, batch_size: Option<usize>) -> PyResult<BatchIterator> {
+    let stream = RecordStream::open(path, ParseOptions::default()).map_err(to_py_err)?;
+    let batches = RecordBatches::new(stream, batch_size.unwrap_or(config::ARROW_BATCH_SIZE));
+    Ok(BatchIterator { batches: Mutex::new(batches) })
+}
+
+/// schema_json() -> str
+///
+/// The Arrow schema of the record batches as JSON.
+#[pyfunction]
+fn schema_json() -> PyResult<String> {
+    arrowbatch::schema_json().map_err(to_py_err)
+}
+
+/// Iterator returned by iter_file
+#[pyclass(module = "xmlpoc")]
+struct RecordIterator {
+    // pyclasses must be Sync, the channel receiver inside the stream is not
+    stream: Mutex<RecordStream>,
+}
+
+#[pymethods]
+impl RecordIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let next = py.detach(|| self.stream.lock().ok().map(|mut stream| stream.next()));
+        match next.ok_or_else(|| ParseError::new_err("record iterator poisoned"))? {
+            Some(Ok(rec)) => record_dict(py, &rec).map(Some),
+            Some(Err(e)) => Err(to_py_err(e)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Iterator returned by iter_file_arrow
+#[pyclass(module = "xmlpoc")]
+struct BatchIterator {
+    batches: Mutex<RecordBatches<RecordStream>>,
+}
+
+#[pymethods]
+impl BatchIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<Py<PyAny>>> {
+        let next = py.detach(|| self.batches.lock().ok().map(|mut batches| batches.next()));
+        match next.ok_or_else(|| ParseError::new_err("batch iterator poisoned"))? {
+            Some(Ok(batch)) => Ok(Some(batch.to_pyarrow(py)?.unbind())),
+            Some(Err(e)) => Err(to_py_err(e)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "xmlpoc")]
+fn xmlpoc_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(parse_file, m)?)?;
+    m.add_function(wrap_pyfunction!(iter_file, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_file_arrow, m)?)?;
+    m.add_function(wrap_pyfunction!(iter_file_arrow, m)?)?;
+    m.add_function(wrap_pyfunction!(schema_json, m)?)?;
+    m.add_class::<RecordIterator>()?;
+    m.add_class::<BatchIterator>()?;
+    m.add("ParseError", m.py().get_type::<ParseError>())?;
+    m.add("COLUMNS", Record::COLUMNS.to_vec())?;
+    Ok(())
+}
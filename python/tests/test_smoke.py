@@ -0,0 +1,46 @@
+# Smoke tests of the Python module against the fixture feeds of the Rust tests.
+#   cd python && maturin develop --extras test && pytest tests
+
+from pathlib import Path
+
+import pytest
+
+import xmlpoc
+
+FIXTURES = Path(__file__).resolve().parents[2] / "tests" / "fixtures"
+SAMPLE = str(FIXTURES / "sample.xml")
+
+
+def test_parse_file_returns_one_dict_per_record():
+    records = xmlpoc.parse_file(SAMPLE)
+
+    assert len(records) == 2
+    assert list(records[0]) == xmlpoc.COLUMNS
+    assert records[0]["ticket_no"] == "2201234567890"
+
+
+def test_iter_file_yields_the_parse_file_records():
+    assert list(xmlpoc.iter_file(SAMPLE)) == xmlpoc.parse_file(SAMPLE)
+
+
+def test_errors_raise_parse_error(tmp_path):
+    with pytest.raises(xmlpoc.ParseError):
+        xmlpoc.parse_file(str(tmp_path / "missing.xml"))
+
+    broken = tmp_path / "broken.xml"
+    broken.write_text('<?xml version="1.0"?>\n<AMA_REV.Feed Version="1.0"><Transaction></AMA_REV.Feed>\n')
+    with pytest.raises(xmlpoc.ParseError):
+        xmlpoc.parse_file(str(broken))
+
+
+def test_arrow_batches_carry_the_records():
+    pa = pytest.importorskip("pyarrow")
+
+    batch = xmlpoc.parse_file_arrow(SAMPLE)
+    assert isinstance(batch, pa.RecordBatch)
+    assert batch.num_rows == 2
+    assert batch.schema.names == xmlpoc.COLUMNS
+
+    batches = list(xmlpoc.iter_file_arrow(SAMPLE, batch_size=1))
+    assert [b.num_rows for b in batches] == [1, 1]
+    assert pa.Table.from_batches(batches).equals(pa.Table.from_batches([batch]))
@@ -0,0 +1,169 @@
+use anyhow::{Result, anyhow};
+use arrow::array::{ArrayRef, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use std::sync::{Arc, OnceLock};
+
+use crate::models::Record;
+
+/// Arrow type of a Record column. Amounts and rates are Float64, the derived day and
+/// minute counts Int64, fx_rate_missing ("Y"/"N") Boolean; everything else stays Utf8,
+/// dates included since their format is set by the date normalization stage.
+/// Every column is nullable, an empty string becomes null.
+pub fn column_type(name: &str) -> DataType {
+    match name {
+        "revenue" | "revenue_reporting_currency" | "exchange_rate" | "fx_rate" => DataType::Float64,
+        "flight_duration_minutes" | "advance_purchase_days" => DataType::Int64,
+        "fx_rate_missing" => DataType::Boolean,
+        _ if name.contains("_amount_") => DataType::Float64,
+        _ => DataType::Utf8,
+    }
+}
+
+/// Schema of the batches built from Records, one field per column in Record::COLUMNS order
+pub fn record_schema() -> SchemaRef {
+    static SCHEMA: OnceLock<SchemaRef> = OnceLock::new();
+    SCHEMA
+        .get_or_init(|| {
+            let fields: Vec<Field> = Record::COLUMNS
+                .iter()
+                .map(|name| Field::new(*name, column_type(name), true))
+                .collect();
+            Arc::new(Schema::new(fields))
+        })
+        .clone()
+}
+
+/// The record schema as JSON: `{"fields": [{"name", "type", "nullable"}, ...]}`
+pub fn schema_json() -> Result<String> {
+    let fields: Vec<serde_json::Value> = record_schema()
+        .fields()
+        .iter()
+        .map(|f| {
+            serde_json::json!({
+                "name": f.name(),
+                "type": f.data_type().to_string(),
+                "nullable": f.is_nullable(),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&serde_json::json!({ "fields": fields }))?)
+}
+
+/// Converts records into one batch with the record schema. A value that does not
+/// parse as its column type fails the conversion, naming the column and row.
+pub fn to_record_batch(records: &[Record]) -> Result<RecordBatch> {
+    let schema = record_schema();
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|f| column_array(records, f.name(), f.data_type()))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema, columns)?)
+}
+
+fn column_array(records: &[Record], name: &str, data_type: &DataType) -> Result<ArrayRef> {
+    let values = records.iter().map(|rec| rec.field(name).unwrap_or_default());
+    let invalid = |row: usize, value: &str| anyhow!("column {} row {}: {:?} is not a valid {}", name, row, value, data_type);
+
+    let array: ArrayRef = match data_type {
+        DataType::Float64 => {
+            let mut builder = Float64Builder::with_capacity(records.len());
+            for (row, value) in values.enumerate() {
+                match value {
+                    "" => builder.append_null(),
+                    v => builder.append_value(v.trim().parse().map_err(|_| invalid(row, v))?),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Int64 => {
+            let mut builder = Int64Builder::with_capacity(records.len());
+            for (row, value) in values.enumerate() {
+                match value {
+                    "" => builder.append_null(),
+                    v => builder.append_value(v.trim().parse().map_err(|_| invalid(row, v))?),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Boolean => {
+            let mut builder = BooleanBuilder::with_capacity(records.len());
+            for (row, value) in values.enumerate() {
+                match value {
+                    "" => builder.append_null(),
+                    "Y" => builder.append_value(true),
+                    "N" => builder.append_value(false),
+                    v => return Err(invalid(row, v)),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        _ => {
+            let mut builder = StringBuilder::with_capacity(records.len(), 0);
+            for value in values {
+                match value {
+                    "" => builder.append_null(),
+                    v => builder.append_value(v),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+    };
+    Ok(array)
+}
+
+/// Batches of at most `batch_size` rows over parsed records, e.g. ParsedFeed::records
+pub fn record_batches(records: &[Record], batch_size: usize) -> impl Iterator<Item = Result<RecordBatch>> + '_ {
+    records.chunks(batch_size.max(1)).map(to_record_batch)
+}
+
+/// Groups a record iterator such as stream::RecordStream into batches of at most
+/// `batch_size` rows. The first error, of the input or of converting a batch, is
+/// returned and ends the iteration; the rows of that batch are not yielded.
+pub struct RecordBatches<I> {
+    records: I,
+    batch_size: usize,
+    done: bool,
+}
+
+impl<I: Iterator<Item = Result<Record>>> RecordBatches<I> {
+    pub fn new(records: I, batch_size: usize) -> Self {
+        Self { records, batch_size: batch_size.max(1), done: false }
+    }
+}
+
+impl<I: Iterator<Item = Result<Record>>> Iterator for RecordBatches<I> {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut batch = Vec::with_capacity(self.batch_size);
+        while batch.len() < self.batch_size {
+            match self.records.next() {
+                Some(Ok(rec)) => batch.push(rec),
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        if batch.is_empty() {
+            return None;
+        }
+        let converted = to_record_batch(&batch);
+        // a batch that fails to convert is not skipped, the iteration ends with its error
+        if converted.is_err() {
+            self.done = true;
+        }
+        Some(converted)
+    }
+}
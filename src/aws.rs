@@ -1,6 +1,11 @@
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::{Client, primitives::ByteStream};
-use anyhow::Result;
+use aws_sdk_s3::types::{ChecksumMode, ObjectCannedAcl, ServerSideEncryption, StorageClass};
+use anyhow::{Context, Result, bail};
+use std::collections::BTreeMap;
+
+use crate::config;
+use crate::storage::{Checksum, ChecksumAlgorithm, ObjectInfo, Storage, check_length};
 
 pub async fn make_s3_client() -> Client {
     let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
@@ -39,13 +44,269 @@ pub async fn get_object_body(client: &Client, key: &str, bucket: &str) -> Result
 }
 
 pub async fn upload_s3_bytes(client: &Client, key: &str, bucket: &str, data: Vec<u8>) -> Result<()> {
-    client
+    upload_s3_object(client, key, bucket, data, &UploadOptions::default(), &BTreeMap::new(), None).await
+}
+
+/// Uploads with the encryption, ACL, storage class and tags of `options`, a content
+/// type from the key's extension and `metadata` as x-amz-meta-* headers. With a
+/// checksum, S3 rejects the upload if the bytes it received do not match.
+pub async fn upload_s3_object(
+    client: &Client,
+    key: &str,
+    bucket: &str,
+    data: Vec<u8>,
+    options: &UploadOptions,
+    metadata: &BTreeMap<String, String>,
+    checksum: Option<&Checksum>,
+) -> Result<()> {
+    let mut request = client
         .put_object()
         .bucket(bucket)
         .key(key)
         .body(ByteStream::from(data))
-        .send()
-        .await?;
+        .set_server_side_encryption(options.encryption.clone())
+        .set_ssekms_key_id(options.kms_key_id.clone())
+        .set_storage_class(options.storage_class.clone());
+
+    if options.bucket_owner_full_control {
+        request = request.acl(ObjectCannedAcl::BucketOwnerFullControl);
+    }
+    if let Some(content_type) = content_type(key) {
+        request = request.content_type(content_type);
+    }
+    if !options.tags.is_empty() {
+        let tagging: Vec<String> = options
+            .tags
+            .iter()
+            .map(|(name, value)| format!("{}={}", percent_encode(name), percent_encode(value)))
+            .collect();
+        request = request.tagging(tagging.join("&"));
+    }
+    for (name, value) in metadata {
+        // metadata travels in HTTP headers, which only carry printable ASCII
+        request = request.metadata(name, header_safe(value));
+    }
+    if let Some(checksum) = checksum {
+        let value = checksum.value.clone();
+        request = match checksum.algorithm {
+            ChecksumAlgorithm::Md5 => request.content_md5(value),
+            ChecksumAlgorithm::Crc32c => request.checksum_crc32_c(value),
+            ChecksumAlgorithm::Sha256 => request.checksum_sha256(value),
+        };
+    }
 
+    request.send().await?;
     Ok(())
 }
+
+/// Settings applied to every object S3Storage writes
+#[derive(Clone, Debug, Default)]
+pub struct UploadOptions {
+    pub encryption: Option<ServerSideEncryption>,
+    /// only with aws:kms; None uses the bucket's default KMS key
+    pub kms_key_id: Option<String>,
+    /// grants the bucket owner full control of objects written into another account's bucket
+    pub bucket_owner_full_control: bool,
+    pub storage_class: Option<StorageClass>,
+    /// object tags, e.g. for lifecycle rules
+    pub tags: Vec<(String, String)>,
+}
+
+impl UploadOptions {
+    /// Options from the UPLOAD_* settings
+    pub fn from_config() -> Result<Self> {
+        let encryption = match config::UPLOAD_SSE {
+            Some(sse) if !ServerSideEncryption::values().contains(&sse) => {
+                bail!("unknown server-side encryption {:?}, expected one of {:?}", sse, ServerSideEncryption::values())
+            }
+            sse => sse.map(ServerSideEncryption::from),
+        };
+        if config::UPLOAD_KMS_KEY_ID.is_some() && encryption != Some(ServerSideEncryption::AwsKms) {
+            bail!("UPLOAD_KMS_KEY_ID needs UPLOAD_SSE = Some(\"aws:kms\")");
+        }
+
+        let storage_class = match config::UPLOAD_STORAGE_CLASS {
+            Some(class) if !StorageClass::values().contains(&class) => {
+                bail!("unknown storage class {:?}, expected one of {:?}", class, StorageClass::values())
+            }
+            class => class.map(StorageClass::from),
+        };
+
+        Ok(Self {
+            encryption,
+            kms_key_id: config::UPLOAD_KMS_KEY_ID.map(str::to_string),
+            bucket_owner_full_control: config::UPLOAD_ACL_BUCKET_OWNER_FULL_CONTROL,
+            storage_class,
+            tags: config::UPLOAD_TAGS.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        })
+    }
+}
+
+// Content type of the outputs the pipeline writes, None leaves it to S3
+fn content_type(key: &str) -> Option<&'static str> {
+    let extension = key.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => Some("text/csv"),
+        "json" => Some("application/json"),
+        "txt" => Some("text/plain; charset=utf-8"),
+        "xml" => Some("application/xml"),
+        "parquet" => Some("application/vnd.apache.parquet"),
+        _ => None,
+    }
+}
+
+// URL-encoding of a tag name or value for the x-amz-tagging header
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Percent-encodes what a header value cannot hold, i.e. non-ASCII and control characters
+fn header_safe(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Storage backed by S3
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    client: Client,
+    upload: UploadOptions,
+}
+
+impl S3Storage {
+    pub fn new(client: Client) -> Self {
+        Self { client, upload: UploadOptions::default() }
+    }
+
+    /// Applies `upload` to every put
+    pub fn with_upload_options(mut self, upload: UploadOptions) -> Self {
+        self.upload = upload;
+        self
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl Storage for S3Storage {
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        let objects = self.list_objects(bucket, prefix).await?;
+        Ok(objects.into_iter().map(|object| object.key).collect())
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        let mut continuation_token: Option<String> = None;
+
+        loop {
+            let page = self
+                .client
+                .list_objects_v2()
+                .bucket(bucket)
+                .prefix(prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await?;
+
+            objects.extend(page.contents().iter().filter_map(|obj| {
+                Some(ObjectInfo {
+                    key: obj.key()?.to_string(),
+                    size: obj.size().and_then(|size| u64::try_from(size).ok()),
+                    etag: obj.e_tag().map(str::to_string),
+                })
+            }));
+
+            match page.next_continuation_token() {
+                Some(token) if page.is_truncated() == Some(true) => continuation_token = Some(token.to_string()),
+                _ => break,
+            }
+        }
+
+        Ok(objects)
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        let body = get_object_body(&self.client, key, bucket).await?;
+        let collected = body.collect().await?;
+        Ok(collected.into_bytes().to_vec())
+    }
+
+    // If-Match makes S3 refuse an object replaced since the listing; checksum mode has
+    // the SDK verify the body against a checksum stored with the object
+    async fn get_verified(&self, bucket: &str, object: &ObjectInfo) -> Result<Vec<u8>> {
+        let resp = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(&object.key)
+            .set_if_match(object.etag.clone())
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await;
+        let resp = match resp {
+            Err(e) if e.raw_response().is_some_and(|r| r.status().as_u16() == 412) => {
+                bail!("refusing object {}: replaced since it was listed", object.key)
+            }
+            resp => resp.with_context(|| format!("downloading {}", object.key))?,
+        };
+
+        if let (Some(listed), Some(received)) = (object.etag.as_deref(), resp.e_tag())
+            && listed.trim_matches('"') != received.trim_matches('"')
+        {
+            bail!("refusing object {}: ETag {} but the listing reports {}", object.key, received, listed);
+        }
+        let declared = resp.content_length();
+
+        let data = resp
+            .body
+            .collect()
+            .await
+            .with_context(|| format!("reading body of {}", object.key))?
+            .into_bytes()
+            .to_vec();
+        if let Some(declared) = declared
+            && data.len() as i64 != declared
+        {
+            bail!("refusing truncated object {}: got {} of {} bytes", object.key, data.len(), declared);
+        }
+        check_length(object, data.len())?;
+        Ok(data)
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        upload_s3_object(&self.client, key, bucket, data, &self.upload, &BTreeMap::new(), None).await
+    }
+
+    async fn put_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        upload_s3_object(&self.client, key, bucket, data, &self.upload, metadata, None).await
+    }
+
+    async fn put_checked(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        metadata: &BTreeMap<String, String>,
+        checksum: &Checksum,
+    ) -> Result<()> {
+        upload_s3_object(&self.client, key, bucket, data, &self.upload, metadata, Some(checksum)).await
+    }
+}
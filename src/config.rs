@@ -1,6 +1,11 @@
 
 
-
+use crate::models::FareColumn;
+use crate::profiles::{FeedProfile, Strictness};
+#[cfg(feature = "protect")]
+use crate::protect::Protection;
+use crate::reconcile::ControlTotal;
+use crate::storage::ChecksumAlgorithm;
 
 // Configuration constants for the ETL process //
 
@@ -11,4 +16,218 @@ pub const CSV_PREFIX : &str = "output_csv_file";
 pub const MAX_ROWS_PER_FILE : usize = 10000usize;
 pub const TIME_FORMAT : &str = "%Y%m%d";
 pub const FOLDER_NAME : &str = "gluejob";
-pub const EXTENSION : &str = ".csv";
\ No newline at end of file
+pub const EXTENSION : &str = ".csv";
+
+// Fare amounts //
+
+pub const FARE_AMOUNT_TYPE : &str = "ACCOUNTED"; // ACCOUNTED, FILED or PAYMENT
+/// FareDescription -> Record column; other descriptions are counted in the run statistics
+pub const FARE_COLUMNS : &[(&str, FareColumn)] = &[
+    ("NET", FareColumn::Net),
+    ("PUBLISHED", FareColumn::Published),
+    ("ADDITIONAL_COLLECTION", FareColumn::AdditionalCollection),
+    ("SELLING", FareColumn::Selling),
+    ("TOTAL", FareColumn::Total),
+    ("EQUIVALENT", FareColumn::Equivalent),
+];
+
+
+// PostgreSQL sink, needs the "pg" feature //
+
+pub const PG_ENABLED : bool = false;
+pub const PG_CONNECTION_ENV : &str = "PG_CONNECTION"; // env var holding the connection string
+pub const PG_STAGING_TABLE : &str = "revenue_staging";
+pub const PG_TARGET_TABLE : Option<&str> = None; // Some("revenue_coupons") to upsert on the natural key, None = staging keeps the current run's rows
+pub const PG_BATCH_SIZE : usize = 5000usize;
+
+
+// SQS worker mode //
+
+pub const SQS_QUEUE_URL : &str = "https://sqs.eu-west-1.amazonaws.com/000000000000/anxi-xml-events";
+pub const SQS_ENDPOINT_URL : Option<&str> = None; // Some("http://localhost:9324") for ElasticMQ
+pub const SQS_WAIT_TIME_SECONDS : i32 = 20;
+pub const SQS_MAX_MESSAGES : i32 = 10;
+pub const SQS_RETRY_MAX_SECONDS : u64 = 60; // failed receives are retried after 1, 2, 4, ... seconds, at most this
+pub const WORKER_DONE_PREFIX : &str = "gluejob/_done/"; // completion markers in OUTPUT_BUCKET, objects of a redelivered message that are done get skipped
+
+
+// Coupon-level tax table //
+
+pub const TAX_OUTPUT_ENABLED : bool = true;
+pub const TAX_CSV_PREFIX : &str = "output_tax_file";
+
+
+// Normalized multi-table output //
+
+pub const NORMALIZED_OUTPUT_ENABLED : bool = false;
+pub const DOCUMENT_CSV_PREFIX : &str = "output_document_file";
+pub const COUPON_CSV_PREFIX : &str = "output_coupon_file";
+pub const FARE_CSV_PREFIX : &str = "output_fare_file";
+pub const TAX_TABLE_CSV_PREFIX : &str = "output_tax_table_file";
+pub const COMMISSION_CSV_PREFIX : &str = "output_commission_file";
+
+
+// Reporting currency conversion //
+
+pub const FX_ENABLED : bool = false;
+pub const REPORTING_CURRENCY : &str = "EUR";
+/// daily rate file (CSV, or Parquet with the "parquet" feature; local path or s3://), None converts with the feed ROE
+pub const FX_RATE_FILE : Option<&str> = Some("s3://anxi-temp-testfiles/reference/fx_daily_rates.csv");
+pub const FX_MAX_LOOKBACK_DAYS : i64 = 3; // use the latest rate up to this many days before the issue date
+/// currency of the accounted amounts when the feed does not give it, None = rate missing
+pub const ACCOUNTING_CURRENCY : Option<&str> = None;
+
+
+// Reference data lookups (CSV, local path or s3://), None disables a dataset //
+
+pub const REFDATA_AIRPORTS : Option<&str> = None; // code,country,region,latitude,longitude,timezone
+pub const REFDATA_CARRIERS : Option<&str> = None; // code,name
+pub const REFDATA_AGENCIES : Option<&str> = None; // iata,name,country
+pub const SHORT_HAUL_MAX_KM : f64 = 1500.0;
+pub const MEDIUM_HAUL_MAX_KM : f64 = 4000.0;
+
+
+// Date-time normalization //
+
+pub const DATE_NORMALIZATION_ENABLED : bool = false; // rewrites issue/departure/arrival in the output formats
+/// accepted input layouts, tried in order (values with a UTC offset are always accepted)
+pub const DATE_INPUT_FORMATS : &[&str] = &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d", "%d%m%y"];
+pub const DATETIME_OUTPUT_FORMAT : &str = "%Y-%m-%dT%H:%M:%S"; // values with a UTC offset keep it, as %:z
+pub const DATE_OUTPUT_FORMAT : &str = "%Y-%m-%d";
+/// fill the departure/arrival UTC columns; local times need the airport timezone of REFDATA_AIRPORTS
+/// and the "timezones" feature, which flight durations use whether or not this is on
+pub const DATE_CONVERT_TO_UTC : bool = false;
+
+
+// Record filters, applied inside the parser to whole transactions, the coupon status being
+// that of the transaction's last coupon. An empty include list keeps every value //
+
+pub const COUPON_STATUS_INCLUDE : &[&str] = &[];
+pub const COUPON_STATUS_EXCLUDE : &[&str] = &[];
+pub const DOCUMENT_STATUS_INCLUDE : &[&str] = &[];
+pub const DOCUMENT_STATUS_EXCLUDE : &[&str] = &[];
+pub const EVENT_TYPE_INCLUDE : &[&str] = &[];
+pub const EVENT_TYPE_EXCLUDE : &[&str] = &[];
+
+
+// Exchange/refund linkage table //
+
+pub const LINK_OUTPUT_ENABLED : bool = true;
+pub const LINK_CSV_PREFIX : &str = "output_link_file";
+
+
+// Daily summary report, written next to the CSV chunks as <prefix>.csv and <prefix>.json //
+
+pub const SUMMARY_ENABLED : bool = false;
+pub const SUMMARY_PREFIX : &str = "daily_summary";
+pub const SUMMARY_DATE_COLUMN : &str = "issue_date"; // date-time values are cut to the day
+pub const SUMMARY_DIMENSIONS : &[&str] = &["validating_carrier", "marketting_carrier", "currency", "cabin", "pos", "distribution_channel"];
+/// output name -> Record column summed under "<name>_total"
+pub const SUMMARY_MEASURES : &[(&str, &str)] = &[
+    ("revenue", "revenue"),
+    ("fare", "cpn_far_fare_amount_accounting_currency"),
+    ("tax", "sum_cpn_txo_tax_amount_accounting_currency"),
+    ("commission", "cpn_std_commission_amount_accounting_currency"),
+];
+
+// Deduplication across files, runs and worker messages. The tax, link and normalized rows
+// of a transaction are written only if its record survives //
+
+pub const DEDUPE_ENABLED : bool = false;
+pub const DEDUPE_KEY : &[&str] = &["primary_ticket_no", "ticket_no", "coupon_no"];
+/// latest transaction_timestamp wins, unless a document status precedence is given
+/// (lowest to highest, e.g. &["ISSUED", "EXCHANGED", "REFUNDED", "VOIDED"])
+pub const DEDUPE_STATUS_PRECEDENCE : Option<&[&str]> = None;
+pub const DEDUPE_MAX_IN_MEMORY : usize = 500_000usize; // records held before a sorted run is spilled
+pub const DEDUPE_SPILL_DIR : Option<&str> = None; // parent of each run's own spill directory, None = system temp dir
+/// keys already written with their precedence, in OUTPUT_BUCKET; None = dedupe within a run only
+pub const DEDUPE_STATE_KEY : Option<&str> = Some("gluejob/_dedupe/keys.jsonl");
+/// keys whose transaction timestamp is older than this many days are dropped from the
+/// state on load and save, resends older than that are written again; None = keep all keys
+pub const DEDUPE_STATE_RETENTION_DAYS : Option<i64> = Some(90);
+
+
+// Feed versions and mapping profiles //
+
+/// "" in versions accepts files that carry neither a Version attribute nor a namespace
+pub const FEED_PROFILES : &[FeedProfile] = &[
+    FeedProfile { name: "ama_rev", root: "AMA_REV.Feed", versions: &["", "1.0", "1.1"], wrappers: &[] },
+];
+/// the version list is not confirmed against every production feed, so other versions are
+/// only reported until it is; Fail stops on them
+pub const UNSUPPORTED_VERSION_POLICY : Strictness = Strictness::Warn;
+pub const MANDATORY_PATHS : &[&str] = &["Transaction", "Transaction/Document", "Transaction/Document/Coupon"];
+pub const MISSING_PATH_POLICY : Strictness = Strictness::Warn;
+
+
+// XSD validation, needs the "validate" feature //
+
+pub const XSD_VALIDATION_ENABLED : bool = false;
+pub const XSD_LOCATION : &str = "s3://anxi-temp-testfiles/reference/AMA_REV_Feed.xsd"; // local path or s3://
+pub const QUARANTINE_PREFIX : &str = "xmlreader/quarantine/"; // non-conforming inputs are copied here
+
+
+// Parallel parsing //
+
+pub const PARALLEL_PARSE_ENABLED : bool = false;
+pub const PARALLEL_CHUNK_BYTES : usize = 16 * 1024 * 1024; // files are cut into slices of about this size
+pub const PARALLEL_PARSE_THREADS : Option<usize> = None; // None = one per core
+
+
+// Arrow export //
+
+pub const ARROW_BATCH_SIZE : usize = 65_536; // rows per RecordBatch
+
+
+// SQL transform, needs the "sql" feature. The query runs once per input file, on that file's
+// records as one table, so aggregations only aggregate within a file; with deduplication it
+// runs once per run (per object in worker mode) on the surviving records. Its result replaces
+// the record CSV output and nothing else: it cannot be combined with PG_ENABLED or
+// SUMMARY_ENABLED, and the tax, link and normalized outputs are not transformed //
+
+pub const SQL_TRANSFORM_FILE : Option<&str> = None; // local path or s3://, e.g. Some("s3://anxi-temp-testfiles/reference/transform.sql")
+pub const SQL_TABLE_NAME : &str = "records";
+
+
+// Reconciliation of parse-time counts with the output and with feed control totals //
+
+pub const RECONCILIATION_POLICY : Strictness = Strictness::Warn; // Fail stops the run on a mismatch
+/// Control totals carried by the feed, none by default. Example:
+/// ControlTotal { metric: "transactions", path: "Trailer", attribute: "TransactionCount" }
+pub const CONTROL_TOTALS : &[ControlTotal] = &[];
+
+
+// PII protection, needs the "protect" feature; applied to every output row before any sink //
+
+/// Record column -> protection; the same column of the tax, linkage and normalized tables
+/// is protected too. Example: &[("pnr_no", Protection::Hash), ("ticket_no", Protection::Tokenize),
+/// ("primary_ticket_no", Protection::Tokenize), ("linked_ticket_no", Protection::Tokenize)]
+/// Drop and Mask are refused on DEDUPE_KEY and PG natural key columns, they would merge rows.
+/// With any column listed, the input snippets of parse errors are logged with their values masked.
+#[cfg(feature = "protect")]
+pub const PII_PROTECTION : &[(&str, Protection)] = &[];
+pub const PII_KEY_ENV : &str = "XMLPOC_PII_KEY"; // secret for Hash and Tokenize
+pub const PII_KEY_FILE : Option<&str> = None; // local path or s3://, read when the env var is unset
+
+
+// Run manifest //
+
+pub const MANIFEST_ENABLED : bool = true;
+pub const MANIFEST_PREFIX : &str = "run_manifest"; // written next to the CSV chunks as <prefix>.json
+
+
+// S3 upload options, applied to every object written //
+
+pub const UPLOAD_SSE : Option<&str> = None; // Some("AES256") for SSE-S3, Some("aws:kms") for SSE-KMS
+pub const UPLOAD_KMS_KEY_ID : Option<&str> = None; // key id or ARN with aws:kms, None = the bucket's default key
+pub const UPLOAD_ACL_BUCKET_OWNER_FULL_CONTROL : bool = false;
+pub const UPLOAD_STORAGE_CLASS : Option<&str> = None; // e.g. Some("STANDARD_IA"), None = STANDARD
+/// Object tags, e.g. &[("retention", "90d"), ("source", "xmlpoc")]
+pub const UPLOAD_TAGS : &[(&str, &str)] = &[];
+/// Sent with every output (Md5 as Content-MD5) for S3 to verify, and recorded in the run manifest
+pub const UPLOAD_CHECKSUM : ChecksumAlgorithm = ChecksumAlgorithm::Sha256;
+
+
+// Logging //
+
+pub const LOG_JSON : bool = false; // errors as one JSON object per line on stderr
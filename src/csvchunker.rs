@@ -1,26 +1,32 @@
-use crate::models::Record;
 use anyhow::{Ok, Result};
 use csv::Writer;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::Path;
-use aws_sdk_s3::Client;
 use tokio::fs;
 use std::path::PathBuf;
 use crate::config;
+use crate::sink::RecordSink;
+use crate::storage::{Storage, source_keys_metadata};
+use serde::Serialize;
 
-pub struct CsvChunkerWriter {
+pub struct CsvChunkerWriter<S: Storage> {
     prefix: String,
     file_index: usize,
     current_rows: usize,
+    total_rows: u64,
     max_rows: usize,
     bucket: String,
-    client: Client,
+    storage: S,
     writer: Writer<File>,
     timestamp: String,
+    // input key of the rows being written and the keys with rows in the current chunk
+    source: String,
+    chunk_sources: Vec<String>,
 }
 
-impl CsvChunkerWriter {
-    pub async fn new(prefix: &str, bucket: &str, max_rows: usize, client: Client, timestamp: &str) -> Result<Self> {
+impl<S: Storage> CsvChunkerWriter<S> {
+    pub async fn new(prefix: &str, bucket: &str, max_rows: usize, storage: S, timestamp: &str) -> Result<Self> {
         let file_index = 1usize;
 
         std::fs::create_dir_all(prefix)?;
@@ -31,11 +37,14 @@ impl CsvChunkerWriter {
             prefix: prefix.to_string(),
             file_index,
             current_rows: 0,
+            total_rows: 0,
             max_rows,
             bucket: bucket.to_string(),
-            client,
+            storage,
             writer,
             timestamp: timestamp.to_string(),
+            source: String::new(),
+            chunk_sources: Vec::new(),
         })
     }
 
@@ -47,6 +56,21 @@ impl CsvChunkerWriter {
         format!("{}/{}/{}_{}{}",config::FOLDER_NAME,self.timestamp,self.prefix,self.file_index, config::EXTENSION)
     }
 
+    /// Input key of the rows written next; each chunk's metadata lists the keys it has rows of
+    pub fn set_source(&mut self, key: &str) {
+        self.source = key.to_string();
+    }
+
+    // row count and source keys of the current chunk
+    fn chunk_metadata(&self) -> BTreeMap<String, String> {
+        let mut metadata = BTreeMap::new();
+        metadata.insert("row-count".to_string(), self.current_rows.to_string());
+        if !self.chunk_sources.is_empty() {
+            metadata.insert("source-keys".to_string(), source_keys_metadata(&self.chunk_sources));
+        }
+        metadata
+    }
+
     async fn rotate(&mut self) -> Result<()> {
         // flush csv writer to ensure content is on disk
         self.writer.flush()?;
@@ -58,8 +82,10 @@ impl CsvChunkerWriter {
         // read the file contents (async)
         let data = fs::read(&filename).await?;
 
-        // upload bytes to S3 (don't shadow `data` variable)
-        crate::aws::upload_s3_bytes(&self.client, &key, &self.bucket, data).await?;
+        // upload bytes to storage (don't shadow `data` variable)
+        self.storage.put_with_metadata(&self.bucket, &key, data, &self.chunk_metadata()).await?;
+
+        self.chunk_sources.clear();
 
         // remove the local file
         if Path::new(&filename).exists() {
@@ -75,16 +101,12 @@ impl CsvChunkerWriter {
         Ok(())
     }
 
-    pub async fn write_record(&mut self, rec: &Record) -> Result<()> {
-        // rotate before writing the next row if reached limit
-        if self.current_rows >= self.max_rows {
-            self.rotate().await?;
-        }
-        self.writer.serialize(rec)?;
-        self.current_rows += 1;
-        Ok(())
+    /// Rows written over all chunks so far
+    pub fn rows_written(&self) -> u64 {
+        self.total_rows
     }
 
+    /// Uploads the last chunk and removes the local working directory
     pub async fn finalize(&mut self) -> Result<()> {
         self.writer.flush()?;
 
@@ -92,7 +114,7 @@ impl CsvChunkerWriter {
         let key = self.key_path();
         let data = fs::read(&filename).await?;
 
-        crate::aws::upload_s3_bytes(&self.client, &key, &self.bucket, data).await?;
+        self.storage.put_with_metadata(&self.bucket, &key, data, &self.chunk_metadata()).await?;
         
         if Path::new(&filename).exists() {
             fs::remove_file(&filename).await?;
@@ -108,3 +130,23 @@ impl CsvChunkerWriter {
 
     }
 }
+
+impl<S: Storage, T: Serialize + Sync> RecordSink<T> for CsvChunkerWriter<S> {
+    async fn write_record(&mut self, rec: &T) -> Result<()> {
+        // rotate before writing the next row if reached limit
+        if self.current_rows >= self.max_rows {
+            self.rotate().await?;
+        }
+        self.writer.serialize(rec)?;
+        if !self.source.is_empty() && self.chunk_sources.last() != Some(&self.source) {
+            self.chunk_sources.push(self.source.clone());
+        }
+        self.current_rows += 1;
+        self.total_rows += 1;
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        CsvChunkerWriter::finalize(self).await
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
+
+use crate::models::Record;
+use crate::refdata::{ReferenceData, split_segment};
+
+// A feed date-time value after parsing
+#[derive(Clone, Copy, Debug)]
+enum FeedTime {
+    Date(NaiveDate),
+    Local(NaiveDateTime),
+    Offset(DateTime<FixedOffset>),
+}
+
+impl FeedTime {
+    fn date(&self) -> NaiveDate {
+        match self {
+            FeedTime::Date(d) => *d,
+            FeedTime::Local(dt) => dt.date(),
+            FeedTime::Offset(dt) => dt.date_naive(),
+        }
+    }
+
+    // UTC instant; an offset gives it directly, local times need the timezone of the
+    // airport they refer to
+    fn to_utc(self, refdata: Option<&ReferenceData>, airport: &str) -> Option<NaiveDateTime> {
+        match self {
+            FeedTime::Date(_) => None,
+            FeedTime::Local(dt) => local_to_utc(dt, refdata?, airport),
+            FeedTime::Offset(dt) => Some(dt.naive_utc()),
+        }
+    }
+}
+
+#[cfg(feature = "timezones")]
+fn local_to_utc(dt: NaiveDateTime, refdata: &ReferenceData, airport: &str) -> Option<NaiveDateTime> {
+    use chrono::TimeZone;
+    let tz = refdata.airport_timezone(airport)?;
+    tz.from_local_datetime(&dt).earliest().map(|t| t.naive_utc())
+}
+
+// without the "timezones" feature local times have no known timezone
+#[cfg(not(feature = "timezones"))]
+fn local_to_utc(_dt: NaiveDateTime, _refdata: &ReferenceData, _airport: &str) -> Option<NaiveDateTime> {
+    None
+}
+
+/// Calendar day of a feed date or date-time value, read the way the date normalization
+/// stage reads it: RFC 3339 first, then `input_formats` in order
+pub fn parse_date<F: AsRef<str>>(value: &str, input_formats: &[F]) -> Option<NaiveDate> {
+    parse_feed_time(value, input_formats).map(|t| t.date())
+}
+
+/// Instant of a feed date-time value for ordering: UTC when it has an offset, the
+/// local clock time otherwise, midnight for a date
+pub fn parse_instant<F: AsRef<str>>(value: &str, input_formats: &[F]) -> Option<NaiveDateTime> {
+    match parse_feed_time(value, input_formats)? {
+        FeedTime::Date(d) => d.and_hms_opt(0, 0, 0),
+        FeedTime::Local(dt) => Some(dt),
+        FeedTime::Offset(dt) => Some(dt.naive_utc()),
+    }
+}
+
+fn parse_feed_time<F: AsRef<str>>(value: &str, input_formats: &[F]) -> Option<FeedTime> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(FeedTime::Offset(dt));
+    }
+
+    for format in input_formats {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(value, format.as_ref()) {
+            return Some(FeedTime::Local(dt));
+        }
+        if let Ok(d) = NaiveDate::parse_from_str(value, format.as_ref()) {
+            return Some(FeedTime::Date(d));
+        }
+    }
+
+    None
+}
+
+pub struct DateNormalizer {
+    input_formats: Vec<String>,
+    datetime_output_format: String,
+    // the datetime format followed by the offset, for values that have one
+    offset_output_format: String,
+    date_output_format: String,
+    convert_to_utc: bool,
+}
+
+impl DateNormalizer {
+    pub fn new(
+        input_formats: &[&str],
+        datetime_output_format: &str,
+        date_output_format: &str,
+        convert_to_utc: bool,
+    ) -> Self {
+        Self {
+            input_formats: input_formats.iter().map(|f| f.to_string()).collect(),
+            datetime_output_format: datetime_output_format.to_string(),
+            offset_output_format: format!("{}%:z", datetime_output_format),
+            date_output_format: date_output_format.to_string(),
+            convert_to_utc,
+        }
+    }
+
+    /// Rewrites issue/departure/arrival in the output format and fills the derived columns.
+    /// Values that match no input format are left as they came.
+    pub fn normalize(&self, rec: &mut Record, refdata: Option<&ReferenceData>) {
+        let issue = self.parse(&rec.issue_date);
+        let dep = self.parse(&rec.dep_date_time);
+        let arr = self.parse(&rec.arr_date_time);
+
+        if let Some(t) = issue {
+            rec.issue_date = self.format(t);
+        }
+        if let Some(t) = dep {
+            rec.dep_date_time = self.format(t);
+        }
+        if let Some(t) = arr {
+            rec.arr_date_time = self.format(t);
+        }
+
+        let (origin_code, dest_code) = split_segment(&rec.segment);
+        let dep_utc = dep.and_then(|t| t.to_utc(refdata, origin_code));
+        let arr_utc = arr.and_then(|t| t.to_utc(refdata, dest_code));
+
+        if self.convert_to_utc {
+            rec.dep_date_time_utc = self.format_utc(dep_utc);
+            rec.arr_date_time_utc = self.format_utc(arr_utc);
+        }
+
+        // a duration is only meaningful between two instants on the same clock
+        rec.flight_duration_minutes = match (dep_utc, arr_utc) {
+            (Some(d), Some(a)) => (a - d).num_minutes().to_string(),
+            _ => String::new(),
+        };
+
+        rec.advance_purchase_days = match (issue, dep) {
+            (Some(i), Some(d)) => (d.date() - i.date()).num_days().to_string(),
+            _ => String::new(),
+        };
+    }
+
+    fn parse(&self, value: &str) -> Option<FeedTime> {
+        parse_feed_time(value, &self.input_formats)
+    }
+
+    fn format(&self, t: FeedTime) -> String {
+        match t {
+            FeedTime::Date(d) => d.format(&self.date_output_format).to_string(),
+            FeedTime::Local(dt) => dt.format(&self.datetime_output_format).to_string(),
+            FeedTime::Offset(dt) => dt.format(&self.offset_output_format).to_string(),
+        }
+    }
+
+    fn format_utc(&self, t: Option<NaiveDateTime>) -> String {
+        t.map(|dt| dt.format(&self.datetime_output_format).to_string())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,391 @@
+use anyhow::{Context, Result, bail};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+use crate::models::{Record, TransactionRows};
+use crate::storage::Storage;
+
+/// How the surviving record of a key is chosen
+#[derive(Clone, Debug)]
+pub enum DedupePolicy {
+    /// highest transaction_timestamp, ties go to the record seen last
+    LatestTimestamp,
+    /// highest document status in this precedence list (lowest first), then timestamp
+    DocumentStatus(Vec<String>),
+}
+
+// Precedence of a record within its key: status rank, then the transaction timestamp
+// as an instant (unparseable timestamps lose to any other)
+type Rank = (usize, Option<NaiveDateTime>);
+
+// A record with its sort key: natural key, precedence and arrival order, and the
+// side output rows of its transaction
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: Vec<String>,
+    rank: Rank,
+    seq: u64,
+    record: Record,
+    #[serde(default)]
+    rows: TransactionRows,
+}
+
+impl Entry {
+    fn sort_key(&self) -> (&[String], &Rank, u64) {
+        (&self.key, &self.rank, self.seq)
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.sort_key() == other.sort_key()
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+/// Precedence of every key written by earlier runs or worker messages. A key is only
+/// written again by a record that outranks it. The whole state is held in memory and
+/// rewritten on save, so one process at a time should use a state object; keys are
+/// kept until [`DedupeState::expire_before`] drops them.
+#[derive(Debug, Default)]
+pub struct DedupeState {
+    ranks: HashMap<Vec<String>, Rank>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateLine {
+    key: Vec<String>,
+    rank: Rank,
+}
+
+impl DedupeState {
+    /// Reads the state saved under `key`; a missing object is an empty state
+    pub async fn load<S: Storage>(storage: &S, bucket: &str, key: &str) -> Result<Self> {
+        let mut ranks = HashMap::new();
+        if storage.list(bucket, key).await?.iter().any(|k| k == key) {
+            let data = storage.get(bucket, key).await?;
+            for line in data.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                let line: StateLine = serde_json::from_slice(line).with_context(|| format!("reading dedupe state {}", key))?;
+                ranks.insert(line.key, line.rank);
+            }
+        }
+        Ok(Self { ranks })
+    }
+
+    pub async fn save<S: Storage>(&self, storage: &S, bucket: &str, key: &str) -> Result<()> {
+        let mut data = Vec::new();
+        for (k, rank) in &self.ranks {
+            serde_json::to_writer(&mut data, &StateLine { key: k.clone(), rank: *rank })?;
+            data.push(b'\n');
+        }
+        storage.put(bucket, key, data).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranks.is_empty()
+    }
+
+    /// Drops the keys whose transaction timestamp is before `cutoff` or unreadable, so
+    /// records that old are written again; returns how many keys were dropped
+    pub fn expire_before(&mut self, cutoff: NaiveDateTime) -> usize {
+        let before = self.ranks.len();
+        self.ranks.retain(|_, (_, timestamp)| timestamp.is_some_and(|t| t >= cutoff));
+        before - self.ranks.len()
+    }
+
+    // Records the winner of a key, false when an earlier one is at least as high
+    fn admit(&mut self, entry: &Entry) -> bool {
+        match self.ranks.get(&entry.key) {
+            Some(rank) if *rank >= entry.rank => false,
+            _ => {
+                self.ranks.insert(entry.key.clone(), entry.rank);
+                true
+            }
+        }
+    }
+}
+
+// Spill directory of one stage, removed with everything in it when dropped
+struct SpillDir(PathBuf);
+
+impl SpillDir {
+    // A fresh directory below `parent`, so concurrent runs never share spill files
+    fn new(parent: &Path) -> Self {
+        static STAGES: AtomicU64 = AtomicU64::new(0);
+        let stage = STAGES.fetch_add(1, AtomicOrdering::Relaxed);
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        Self(parent.join(format!("xmlpoc_dedupe_{}_{}_{}", std::process::id(), nanos, stage)))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Keeps one record per natural key with bounded memory: records are buffered up to
+/// `max_in_memory`, then sorted and spilled to disk as a run; finish() merges the runs.
+pub struct DedupeStage {
+    key_columns: Vec<String>,
+    policy: DedupePolicy,
+    timestamp_formats: Vec<String>,
+    max_in_memory: usize,
+    spill_dir: SpillDir,
+    buffer: Vec<Entry>,
+    runs: Vec<PathBuf>,
+    next_seq: u64,
+    removed: usize,
+    state: DedupeState,
+}
+
+impl DedupeStage {
+    /// `spill_parent` holds the run's own spill directory (None = the system temp
+    /// dir); transaction timestamps are read with `timestamp_formats` besides RFC 3339.
+    pub fn new(
+        key_columns: &[&str],
+        policy: DedupePolicy,
+        timestamp_formats: &[&str],
+        max_in_memory: usize,
+        spill_parent: Option<&str>,
+    ) -> Result<Self> {
+        let probe = Record::default();
+        for column in key_columns {
+            if probe.field(column).is_none() {
+                bail!("dedupe key column {:?} is not a Record column", column);
+            }
+        }
+
+        Ok(Self {
+            key_columns: key_columns.iter().map(|c| c.to_string()).collect(),
+            policy,
+            timestamp_formats: timestamp_formats.iter().map(|f| f.to_string()).collect(),
+            max_in_memory: max_in_memory.max(1),
+            spill_dir: SpillDir::new(&spill_parent.map(PathBuf::from).unwrap_or_else(std::env::temp_dir)),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            next_seq: 0,
+            removed: 0,
+            state: DedupeState::default(),
+        })
+    }
+
+    /// Starts from the keys written before, see [`DedupeState`]
+    pub fn with_state(mut self, state: DedupeState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn push(&mut self, record: Record) -> Result<()> {
+        self.push_transaction(record, TransactionRows::default())
+    }
+
+    /// Like push, with the tax, link and normalized rows of the record's transaction;
+    /// they are only handed out again if the record survives
+    pub fn push_transaction(&mut self, record: Record, rows: TransactionRows) -> Result<()> {
+        let key = self
+            .key_columns
+            .iter()
+            .map(|c| record.field(c).unwrap_or_default().to_string())
+            .collect();
+
+        let status_rank = match &self.policy {
+            DedupePolicy::LatestTimestamp => 0,
+            DedupePolicy::DocumentStatus(precedence) => precedence
+                .iter()
+                .position(|s| *s == record.document_status)
+                .map(|i| i + 1)
+                .unwrap_or(0),
+        };
+
+        let timestamp = crate::datetimes::parse_instant(&record.transaction_timestamp, &self.timestamp_formats);
+        self.buffer.push(Entry {
+            key,
+            rank: (status_rank, timestamp),
+            seq: self.next_seq,
+            record,
+            rows,
+        });
+        self.next_seq += 1;
+
+        if self.buffer.len() >= self.max_in_memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Merges the spilled runs and the in-memory buffer into one deduplicated stream
+    pub fn finish(mut self) -> Result<DedupedRecords> {
+        let last_run = self.sorted_buffer();
+
+        let mut sources = Vec::with_capacity(self.runs.len() + 1);
+        for path in &self.runs {
+            sources.push(RunSource::File(BufReader::new(File::open(path)?).lines()));
+        }
+        sources.push(RunSource::Memory(last_run.into_iter()));
+
+        let mut heap = BinaryHeap::new();
+        for (i, source) in sources.iter_mut().enumerate() {
+            if let Some(entry) = source.next_entry()? {
+                heap.push(Reverse(HeapItem { entry, source: i }));
+            }
+        }
+
+        Ok(DedupedRecords {
+            sources,
+            heap,
+            pending: None,
+            _spill_dir: self.spill_dir,
+            removed: self.removed,
+            state: self.state,
+        })
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let run = self.sorted_buffer();
+
+        fs::create_dir_all(&self.spill_dir.0)?;
+        let path = self.spill_dir.0.join(format!("run_{}.jsonl", self.runs.len() + 1));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for entry in &run {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        self.runs.push(path);
+        Ok(())
+    }
+
+    // Sorts the buffer and keeps the last (winning) entry of every key
+    fn sorted_buffer(&mut self) -> Vec<Entry> {
+        let mut entries = std::mem::take(&mut self.buffer);
+        entries.sort_unstable();
+
+        let mut run: Vec<Entry> = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if let Some(last) = run.last_mut()
+                && last.key == entry.key
+            {
+                *last = entry;
+                self.removed += 1;
+                continue;
+            }
+            run.push(entry);
+        }
+        run
+    }
+}
+
+enum RunSource {
+    File(Lines<BufReader<File>>),
+    Memory(std::vec::IntoIter<Entry>),
+}
+
+impl RunSource {
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        match self {
+            RunSource::File(lines) => match lines.next() {
+                Some(line) => Ok(Some(serde_json::from_str(&line?)?)),
+                None => Ok(None),
+            },
+            RunSource::Memory(entries) => Ok(entries.next()),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct HeapItem {
+    entry: Entry,
+    source: usize,
+}
+
+/// The surviving records in natural key order; spill files are removed on drop
+pub struct DedupedRecords {
+    sources: Vec<RunSource>,
+    heap: BinaryHeap<Reverse<HeapItem>>,
+    pending: Option<Entry>,
+    _spill_dir: SpillDir,
+    removed: usize,
+    state: DedupeState,
+}
+
+impl DedupedRecords {
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        Ok(self.next_transaction()?.map(|(record, _)| record))
+    }
+
+    /// The next surviving record with the rows pushed along with it
+    pub fn next_transaction(&mut self) -> Result<Option<(Record, TransactionRows)>> {
+        // winners of keys that earlier runs wrote with at least the same rank are dropped
+        while let Some(entry) = self.next_winner()? {
+            if self.state.admit(&entry) {
+                return Ok(Some((entry.record, entry.rows)));
+            }
+            self.removed += 1;
+        }
+        Ok(None)
+    }
+
+    // The highest ranked entry of the next key
+    fn next_winner(&mut self) -> Result<Option<Entry>> {
+        loop {
+            let Some(Reverse(item)) = self.heap.pop() else {
+                return Ok(self.pending.take());
+            };
+
+            if let Some(entry) = self.sources[item.source].next_entry()? {
+                self.heap.push(Reverse(HeapItem { entry, source: item.source }));
+            }
+
+            // entries of a key arrive in ascending precedence, the last one wins
+            match self.pending.take() {
+                Some(prev) if prev.key == item.entry.key => {
+                    self.pending = Some(item.entry);
+                    self.removed += 1;
+                }
+                Some(prev) => {
+                    self.pending = Some(item.entry);
+                    return Ok(Some(prev));
+                }
+                None => self.pending = Some(item.entry),
+            }
+        }
+    }
+
+    /// Records dropped as duplicates so far, within the run or of earlier runs
+    pub fn removed(&self) -> usize {
+        self.removed
+    }
+
+    /// Key state including the records written by this run, to be saved once they are
+    pub fn into_state(self) -> DedupeState {
+        self.state
+    }
+}
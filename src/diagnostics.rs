@@ -0,0 +1,177 @@
+use serde::Serialize;
+use std::fmt;
+
+use crate::config;
+
+// Bytes of input shown on each side of the failing position
+const SNIPPET_RADIUS: usize = 40;
+
+/// Where and why parsing a feed file failed. The parser fills the offset and element
+/// path; locate() adds the key, line, column and snippet from the downloaded bytes.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ParseError {
+    pub key: String,
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub path: Vec<String>,
+    pub snippet: String,
+    /// chars of the snippet before the offset, for the caret of the pretty output
+    #[serde(skip)]
+    pub snippet_caret: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn at(offset: usize, path: &[&str], cause: &anyhow::Error) -> Self {
+        Self {
+            offset,
+            path: path.iter().map(|s| s.to_string()).collect(),
+            message: format!("{:#}", cause),
+            ..Default::default()
+        }
+    }
+
+    fn with_source(mut self, key: &str, data: &[u8]) -> Self {
+        let (line, column) = line_col(data, self.offset);
+        self.key = key.to_string();
+        self.line = line;
+        self.column = column;
+        (self.snippet, self.snippet_caret) = snippet(data, self.offset);
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "failed to parse {:?} at line {}, column {} (byte {})", self.key, self.line, self.column, self.offset)?;
+        writeln!(f, "  path:  {}", if self.path.is_empty() { "-".to_string() } else { self.path.join("/") })?;
+        if !self.snippet.is_empty() {
+            writeln!(f, "  near:  {}", self.snippet)?;
+            writeln!(f, "         {}^", " ".repeat(self.snippet_caret))?;
+        }
+        write!(f, "  cause: {}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Adds the source key and position details to a parse error; other errors only get the key
+pub fn locate(err: anyhow::Error, key: &str, data: &[u8]) -> anyhow::Error {
+    match err.downcast::<ParseError>() {
+        Ok(parse_error) => parse_error.with_source(key, data).into(),
+        Err(err) => err.context(format!("failed to parse {:?}", key)),
+    }
+}
+
+/// Prints a failure on stderr, as one JSON object per line when LOG_JSON is set
+pub fn log_error(context: &str, err: &anyhow::Error) {
+    let parse_error = err.chain().find_map(|e| e.downcast_ref::<ParseError>());
+
+    if !config::LOG_JSON {
+        eprintln!("{}: {:#}", context, err);
+        return;
+    }
+
+    let mut fields = serde_json::json!({
+        "level": "error",
+        "context": context,
+        "message": format!("{:#}", err),
+    });
+    if let Some(parse_error) = parse_error
+        && let Ok(serde_json::Value::Object(extra)) = serde_json::to_value(parse_error)
+    {
+        // the message becomes the bare cause, the position goes into its own fields
+        for (name, value) in extra {
+            fields[name.as_str()] = value;
+        }
+    }
+    eprintln!("{}", fields);
+}
+
+/// 1-based line and column of a byte offset
+pub fn line_col(data: &[u8], offset: usize) -> (usize, usize) {
+    let before = &data[..offset.min(data.len())];
+    let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+    let column = before.iter().rev().take_while(|b| **b != b'\n').count() + 1;
+    (line, column)
+}
+
+// Input around the offset on a single line, and the number of chars before the offset
+fn snippet(data: &[u8], offset: usize) -> (String, usize) {
+    let offset = offset.min(data.len());
+    let start = offset.saturating_sub(SNIPPET_RADIUS);
+    let end = (offset + SNIPPET_RADIUS).min(data.len());
+
+    let flatten = |bytes: &[u8]| {
+        String::from_utf8_lossy(bytes)
+            .chars()
+            .map(|c| if c.is_whitespace() { ' ' } else { c })
+            .collect::<String>()
+    };
+    let before = flatten(&data[start..offset]);
+    let caret = before.chars().count();
+    let snippet = before + &flatten(&data[offset..end]);
+    if protection_configured() { (redact_snippet(&snippet), caret) } else { (snippet, caret) }
+}
+
+// Snippets can show the values PII protection hides in the outputs
+fn protection_configured() -> bool {
+    #[cfg(feature = "protect")]
+    let configured = !config::PII_PROTECTION.is_empty();
+    #[cfg(not(feature = "protect"))]
+    let configured = false;
+    configured
+}
+
+/// Masks the text content, attribute values, comments and CDATA of an XML snippet
+/// with `*`, one per char so the caret still points at the same place; element and
+/// attribute names stay. Input before the first `<` could be any of these and is masked.
+pub fn redact_snippet(snippet: &str) -> String {
+    #[derive(PartialEq)]
+    enum State {
+        Text,
+        Tag,
+        Quoted(char),
+        Declaration,
+    }
+
+    let mut state = State::Text;
+    let mut out = String::with_capacity(snippet.len());
+    let mut chars = snippet.chars().peekable();
+    while let Some(c) = chars.next() {
+        let keep = match state {
+            State::Text if c == '<' => {
+                state = match chars.peek() {
+                    Some('!') => State::Declaration,
+                    _ => State::Tag,
+                };
+                true
+            }
+            State::Text | State::Declaration if c == ' ' => true,
+            State::Text => false,
+            State::Declaration => {
+                if c == '>' {
+                    state = State::Text;
+                }
+                c == '>'
+            }
+            State::Tag => {
+                match c {
+                    '"' | '\'' => state = State::Quoted(c),
+                    '>' => state = State::Text,
+                    _ => {}
+                }
+                true
+            }
+            State::Quoted(quote) => {
+                if c == quote {
+                    state = State::Tag;
+                }
+                c == quote
+            }
+        };
+        out.push(if keep { c } else { '*' });
+    }
+    out
+}
@@ -0,0 +1,210 @@
+use anyhow::{Context, Result, bail};
+use chrono::{Duration, NaiveDate};
+#[cfg(feature = "parquet")]
+use parquet::file::reader::{FileReader, SerializedFileReader};
+#[cfg(feature = "parquet")]
+use parquet::record::Field;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::models::Record;
+use crate::storage::Storage;
+
+/// Where conversion rates come from
+#[derive(Clone, Copy, Debug)]
+pub enum FxRateSource {
+    /// the ROE of the accounted fare, taken as reporting currency units per unit of
+    /// the accounting currency
+    FeedRoe,
+    /// daily rate file (CSV or Parquet, local path or s3://bucket/key) with the columns
+    /// date, from_currency, to_currency, rate
+    RateFile(&'static str),
+}
+
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    date: NaiveDate,
+    from_currency: String,
+    to_currency: String,
+    rate: f64,
+}
+
+pub struct FxConverter {
+    reporting_currency: String,
+    source: FxRateSource,
+    // (from, to) -> date -> rate
+    rates: HashMap<(String, String), BTreeMap<NaiveDate, f64>>,
+    max_lookback_days: i64,
+    // for records whose amounts carry no currency
+    accounting_currency: Option<String>,
+    // issue dates are read before the date stage rewrites them
+    date_formats: Vec<String>,
+}
+
+impl FxConverter {
+    pub async fn load<S: Storage>(
+        storage: &S,
+        reporting_currency: &str,
+        source: FxRateSource,
+        max_lookback_days: i64,
+        accounting_currency: Option<&str>,
+        date_formats: &[&str],
+    ) -> Result<Self> {
+        let mut rates: HashMap<(String, String), BTreeMap<NaiveDate, f64>> = HashMap::new();
+
+        if let FxRateSource::RateFile(location) = source {
+            let data = crate::storage::read_location(storage, location)
+                .await
+                .with_context(|| format!("reading fx rates from {}", location))?;
+
+            let rows = if location.to_lowercase().ends_with(".parquet") {
+                read_parquet_rates(data)?
+            } else {
+                read_csv_rates(&data)?
+            };
+
+            for row in rows {
+                rates
+                    .entry((row.from_currency, row.to_currency))
+                    .or_default()
+                    .insert(row.date, row.rate);
+            }
+        }
+
+        Ok(Self {
+            reporting_currency: reporting_currency.to_string(),
+            source,
+            rates,
+            max_lookback_days,
+            accounting_currency: accounting_currency.map(str::to_string),
+            date_formats: date_formats.iter().map(|f| f.to_string()).collect(),
+        })
+    }
+
+    /// Fills the reporting currency columns of a record, or flags it when no rate is found
+    pub fn convert(&self, rec: &mut Record) {
+        rec.reporting_currency = self.reporting_currency.clone();
+
+        let Some(rate) = self.rate_for(rec) else {
+            rec.fx_rate.clear();
+            rec.fx_rate_missing = "Y".to_string();
+            return;
+        };
+
+        rec.fx_rate = rate.to_string();
+        rec.fx_rate_missing = "N".to_string();
+
+        let columns = [
+            (&rec.revenue, &mut rec.revenue_reporting_currency),
+            (&rec.cpn_far_fare_amount_accounting_currency, &mut rec.cpn_far_fare_amount_reporting_currency),
+            (&rec.net_fare_amount_accounting_currency, &mut rec.net_fare_amount_reporting_currency),
+            (&rec.pub_fare_amount_accounting_currency, &mut rec.pub_fare_amount_reporting_currency),
+            (&rec.bal_exchange_additional_collected_fare_amount_accounting_currency, &mut rec.bal_exchange_additional_collected_fare_amount_reporting_currency),
+            (&rec.selling_fare_amount_accounting_currency, &mut rec.selling_fare_amount_reporting_currency),
+            (&rec.total_fare_amount_accounting_currency, &mut rec.total_fare_amount_reporting_currency),
+            (&rec.equivalent_fare_amount_accounting_currency, &mut rec.equivalent_fare_amount_reporting_currency),
+            (&rec.cpn_std_commission_amount_accounting_currency, &mut rec.cpn_std_commission_amount_reporting_currency),
+            (&rec.std_commission_amount_accounting_currency, &mut rec.std_commission_amount_reporting_currency),
+            (&rec.sup_commision_amount_accounting_currency, &mut rec.sup_commision_amount_reporting_currency),
+            (&rec.sum_cpn_txo_tax_amount_accounting_currency, &mut rec.sum_cpn_txo_tax_amount_reporting_currency),
+            (&rec.cpn_txo_tax_amount_accounting_currency_yq, &mut rec.cpn_txo_tax_amount_reporting_currency_yq),
+        ];
+
+        for (amount, converted) in columns {
+            // empty source columns stay empty
+            *converted = match amount.parse::<f64>() {
+                Ok(value) => (value * rate).to_string(),
+                Err(_) => String::new(),
+            };
+        }
+    }
+
+    // Rate from the accounting currency, not the currency of payment, to the reporting one
+    fn rate_for(&self, rec: &Record) -> Option<f64> {
+        let currency = match rec.accounting_currency.as_str() {
+            "" => self.accounting_currency.as_deref()?,
+            currency => currency,
+        };
+        if currency == self.reporting_currency {
+            return Some(1.0);
+        }
+
+        match self.source {
+            FxRateSource::FeedRoe => rec.exchange_rate.parse::<f64>().ok().filter(|r| *r > 0.0),
+            FxRateSource::RateFile(_) => {
+                let date = crate::datetimes::parse_date(&rec.issue_date, &self.date_formats)?;
+                self.file_rate(currency, date)
+            }
+        }
+    }
+
+    // Latest rate on or before `date` (within the lookback window), trying the inverse
+    // pair when only that one is published
+    fn file_rate(&self, currency: &str, date: NaiveDate) -> Option<f64> {
+        let earliest = date - Duration::days(self.max_lookback_days);
+        let lookup = |from: &str, to: &str| {
+            self.rates
+                .get(&(from.to_string(), to.to_string()))
+                .and_then(|by_date| by_date.range(earliest..=date).next_back())
+                .map(|(_, rate)| *rate)
+        };
+
+        lookup(currency, &self.reporting_currency)
+            .or_else(|| lookup(&self.reporting_currency, currency).filter(|r| *r > 0.0).map(|r| 1.0 / r))
+    }
+}
+
+fn read_csv_rates(data: &[u8]) -> Result<Vec<RateRow>> {
+    let mut reader = csv::Reader::from_reader(data);
+    let mut rows = Vec::new();
+    for row in reader.deserialize() {
+        rows.push(row?);
+    }
+    Ok(rows)
+}
+
+#[cfg(feature = "parquet")]
+fn read_parquet_rates(data: Vec<u8>) -> Result<Vec<RateRow>> {
+    let reader = SerializedFileReader::new(bytes::Bytes::from(data))?;
+    let mut rows = Vec::new();
+
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        let mut date = None;
+        let mut from_currency = None;
+        let mut to_currency = None;
+        let mut rate = None;
+
+        for (name, field) in row.get_column_iter() {
+            match (name.as_str(), field) {
+                ("date", Field::Date(days)) => {
+                    date = NaiveDate::from_ymd_opt(1970, 1, 1)
+                        .map(|epoch| epoch + Duration::days(i64::from(*days)));
+                }
+                ("date", Field::Str(s)) => date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok(),
+                ("from_currency", Field::Str(s)) => from_currency = Some(s.clone()),
+                ("to_currency", Field::Str(s)) => to_currency = Some(s.clone()),
+                ("rate", Field::Double(v)) => rate = Some(*v),
+                ("rate", Field::Float(v)) => rate = Some(f64::from(*v)),
+                _ => {}
+            }
+        }
+
+        match (date, from_currency, to_currency, rate) {
+            (Some(date), Some(from_currency), Some(to_currency), Some(rate)) => rows.push(RateRow {
+                date,
+                from_currency,
+                to_currency,
+                rate,
+            }),
+            _ => bail!("fx rate parquet row is missing date, from_currency, to_currency or rate"),
+        }
+    }
+
+    Ok(rows)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn read_parquet_rates(_data: Vec<u8>) -> Result<Vec<RateRow>> {
+    bail!("fx rate files in Parquet need the \"parquet\" feature")
+}
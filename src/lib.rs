@@ -0,0 +1,71 @@
+//! Reader for the Amadeus revenue accounting feed (`AMA_REV.Feed`) and the ETL
+//! pipeline built on it.
+//!
+//! - [`parser`] turns a feed file into flat [`Record`]s plus tax and linkage rows;
+//!   [`normalized`] emits one table per entity instead, and [`parallel`] parses
+//!   large in-memory files on all cores. [`stream::RecordStream`] yields the records
+//!   of a file one by one with bounded memory.
+//! - [`arrowbatch`] converts records into Arrow `RecordBatch`es with a typed schema;
+//!   with the `sql` feature, `sqltransform` runs a user-supplied query over them.
+//! - [`sink::RecordSink`] is implemented by the chunked CSV writer ([`csvchunker`]),
+//!   the daily totals of [`summary`] and, with the `pg` feature, the PostgreSQL sink
+//!   `pgsink`.
+//! - Optional stages come with features, all on by default: `validate` (XSD
+//!   validation, links the system libxml2), `protect` (PII protection), `parquet`
+//!   (Parquet FX rate files) and `timezones` (airport timezones for UTC times). With
+//!   `default-features = false` the crate only needs what parsing needs.
+//! - [`storage::Storage`] abstracts where files are read from and written to:
+//!   [`storage::LocalStorage`] for a directory tree and, with the `aws` feature
+//!   (on by default), `aws::S3Storage`.
+//! - [`pipeline`] wires everything together the way the `xmlpoc` binary runs it,
+//!   with the settings of [`config`].
+//!
+//! ```no_run
+//! use quick_xml::Reader;
+//!
+//! let data = std::fs::read("feed.xml")?;
+//! let mut reader = Reader::from_reader(data.as_slice());
+//! reader.trim_text(true);
+//! let feed = xmlpoc::parse_xml(&mut reader)?;
+//! println!("{} records", feed.records.len());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod arrowbatch;
+#[cfg(feature = "aws")]
+pub mod aws;
+pub mod config;
+pub mod csvchunker;
+pub mod datetimes;
+pub mod dedupe;
+pub mod diagnostics;
+pub mod fx;
+pub mod manifest;
+pub mod models;
+pub mod normalized;
+pub mod parallel;
+pub mod parser;
+#[cfg(feature = "pg")]
+pub mod pgsink;
+pub mod pipeline;
+pub mod profiles;
+#[cfg(feature = "protect")]
+pub mod protect;
+pub mod reconcile;
+pub mod refdata;
+pub mod sink;
+#[cfg(feature = "sql")]
+pub mod sqltransform;
+#[cfg(feature = "aws")]
+pub mod sqs;
+pub mod storage;
+pub mod stream;
+pub mod summary;
+#[cfg(feature = "validate")]
+pub mod validate;
+
+pub use models::{ParsedFeed, Record};
+pub use parser::{ParseOptions, parse_xml, parse_xml_with_options};
+pub use sink::RecordSink;
+pub use storage::{LocalStorage, Storage};
+pub use stream::RecordStream;
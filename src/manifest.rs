@@ -0,0 +1,43 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::config;
+use crate::storage::{Storage, UploadedObject, source_keys_metadata};
+
+/// Record of one run (or, in worker mode, of one object), written as JSON next to
+/// the CSV chunks once the outputs are complete
+#[derive(Clone, Debug, Serialize)]
+pub struct RunManifest {
+    pub run_timestamp: String,
+    pub started_at: String,
+    pub finished_at: String,
+    /// input keys, in processing order
+    pub inputs: Vec<String>,
+    /// every output object with its size and checksum
+    pub outputs: Vec<UploadedObject>,
+    /// PII protection settings, null when no column is protected
+    pub protection: serde_json::Value,
+}
+
+impl RunManifest {
+    pub fn new(timestamp: &str) -> Self {
+        Self {
+            run_timestamp: timestamp.to_string(),
+            started_at: Utc::now().to_rfc3339(),
+            finished_at: String::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            protection: serde_json::Value::Null,
+        }
+    }
+
+    /// Stamps the finish time and uploads the manifest as `<prefix>.json`
+    pub async fn write<S: Storage>(&mut self, storage: &S, bucket: &str, prefix: &str) -> Result<()> {
+        self.finished_at = Utc::now().to_rfc3339();
+        let key = format!("{}/{}/{}.json", config::FOLDER_NAME, self.run_timestamp, prefix);
+        let metadata = BTreeMap::from([("source-keys".to_string(), source_keys_metadata(&self.inputs))]);
+        storage.put_with_metadata(bucket, &key, serde_json::to_vec_pretty(self)?, &metadata).await
+    }
+}
@@ -1,39 +1,427 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Clone, Debug, Default, Serialize)]
-pub struct Record {
+/// Mutable access to the text columns of an output row by name, for stages that
+/// rewrite values such as the PII protection
+pub trait TextColumns {
+    fn field_mut(&mut self, name: &str) -> Option<&mut String>;
+}
+
+macro_rules! text_columns {
+    ($row:ty { $($field:ident),* $(,)? }) => {
+        impl TextColumns for $row {
+            fn field_mut(&mut self, name: &str) -> Option<&mut String> {
+                match name {
+                    $(stringify!($field) => Some(&mut self.$field),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+// The Record struct together with its column access by name, for stages configured
+// with column lists; both come from the one field list below.
+macro_rules! record_struct {
+    ($(#[$attr:meta])* pub struct $name:ident { $($(#[$field_attr:meta])* pub $field:ident: String,)* }) => {
+        $(#[$attr])*
+        pub struct $name {
+            $($(#[$field_attr])* pub $field: String,)*
+        }
+
+        impl $name {
+            /// Field names in declaration order, the same as the CSV header
+            pub const COLUMNS: &[&str] = &[$(stringify!($field)),*];
+
+            pub fn field(&self, name: &str) -> Option<&str> {
+                match name {
+                    $(stringify!($field) => Some(self.$field.as_str()),)*
+                    _ => None,
+                }
+            }
+        }
+
+        text_columns!($name { $($field),* });
+    };
+}
+
+record_struct! {
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    pub struct Record {
+        pub primary_ticket_no: String,
+        pub ticket_no: String,
+        pub coupon_no: String,
+        pub issue_date: String,
+        pub coupon_status: String,
+        pub segment: String,
+        pub flight_nr: String,
+        pub dep_date_time: String,
+        pub arr_date_time: String,
+        pub cabin: String,
+        pub rbd: String,
+        pub pos: String,
+        pub iata: String,
+        pub distribution_channel: String,
+        pub fare_basis: String,
+        pub pnr_no: String,
+        pub revenue: String,
+        pub currency: String,
+        pub tour_code: String,
+        pub cpn_far_fare_amount_accounting_currency: String,
+        pub net_fare_amount_accounting_currency: String,
+        pub pub_fare_amount_accounting_currency: String,
+        pub bal_exchange_additional_collected_fare_amount_accounting_currency: String,
+        pub cpn_std_commission_amount_accounting_currency: String,
+        pub std_commission_amount_accounting_currency: String,
+        pub sup_commision_amount_accounting_currency: String,
+        /// coupon taxes of the record's own transaction, summed over all its coupons
+        pub sum_cpn_txo_tax_amount_accounting_currency: String,
+        pub cpn_txo_tax_amount_accounting_currency_yq: String,
+        pub exchange_rate: String,
+        pub document_status: String,
+        pub trx_revenue_attributable_iata_number: String,
+        pub marketting_carrier: String,
+        pub operating_carrier: String,
+        pub validating_carrier: String,
+        /// reporting currency columns, filled by the optional fx stage
+        pub reporting_currency: String,
+        pub fx_rate: String,
+        pub fx_rate_missing: String,
+        pub revenue_reporting_currency: String,
+        pub cpn_far_fare_amount_reporting_currency: String,
+        pub net_fare_amount_reporting_currency: String,
+        pub pub_fare_amount_reporting_currency: String,
+        pub bal_exchange_additional_collected_fare_amount_reporting_currency: String,
+        pub selling_fare_amount_reporting_currency: String,
+        pub total_fare_amount_reporting_currency: String,
+        pub equivalent_fare_amount_reporting_currency: String,
+        pub cpn_std_commission_amount_reporting_currency: String,
+        pub std_commission_amount_reporting_currency: String,
+        pub sup_commision_amount_reporting_currency: String,
+        pub sum_cpn_txo_tax_amount_reporting_currency: String,
+        pub cpn_txo_tax_amount_reporting_currency_yq: String,
+        /// reference data columns, filled by the optional lookup stage
+        pub origin_country: String,
+        pub origin_region: String,
+        pub destination_country: String,
+        pub destination_region: String,
+        pub haul_type: String,
+        pub marketting_carrier_name: String,
+        pub operating_carrier_name: String,
+        pub validating_carrier_name: String,
+        pub agency_name: String,
+        pub agency_country: String,
+        /// derived date-time columns, filled by the date normalization stage
+        pub dep_date_time_utc: String,
+        pub arr_date_time_utc: String,
+        pub flight_duration_minutes: String,
+        pub advance_purchase_days: String,
+        /// first document referenced by the transaction (original/exchanged/refunded ticket)
+        pub linked_ticket_no: String,
+        pub link_type: String,
+        /// SELLING, TOTAL and EQUIVALENT fare amounts, see config::FARE_COLUMNS; new columns go last
+        pub selling_fare_amount_accounting_currency: String,
+        pub total_fare_amount_accounting_currency: String,
+        pub equivalent_fare_amount_accounting_currency: String,
+        /// transaction timestamp, the rank of latest-wins deduplication
+        pub transaction_timestamp: String,
+        /// currency of the `*_accounting_currency` amounts, from their Currency attribute
+        pub accounting_currency: String,
+    }
+}
+
+/// Record columns a Fares/Fare amount can be written to, see config::FARE_COLUMNS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FareColumn {
+    Net,
+    Published,
+    AdditionalCollection,
+    Selling,
+    Total,
+    Equivalent,
+}
+
+impl FareColumn {
+    pub fn field_mut(self, rec: &mut Record) -> &mut String {
+        match self {
+            FareColumn::Net => &mut rec.net_fare_amount_accounting_currency,
+            FareColumn::Published => &mut rec.pub_fare_amount_accounting_currency,
+            FareColumn::AdditionalCollection => &mut rec.bal_exchange_additional_collected_fare_amount_accounting_currency,
+            FareColumn::Selling => &mut rec.selling_fare_amount_accounting_currency,
+            FareColumn::Total => &mut rec.total_fare_amount_accounting_currency,
+            FareColumn::Equivalent => &mut rec.equivalent_fare_amount_accounting_currency,
+        }
+    }
+}
+
+/// One row per coupon-level tax, keyed by ticket/coupon
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaxRecord {
     pub primary_ticket_no: String,
     pub ticket_no: String,
     pub coupon_no: String,
+    pub nature_code: String,
+    pub iso_code: String,
+    pub is_refundable: String,
+    pub amount_accounting_currency: String,
+}
+
+/// One reference from a document or coupon to another document/coupon, so a ticket
+/// can be followed across exchanges, reissues and refunds
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DocumentLinkRecord {
+    pub primary_ticket_no: String,
+    pub ticket_no: String,
+    pub coupon_no: String, // empty for document-level references
+    pub document_status: String,
+    pub link_type: String, // ReferenceType from the feed, e.g. EXCHANGE, REISSUE, REFUND
+    pub linked_ticket_no: String,
+    pub linked_coupon_no: String,
+    pub linked_issue_date: String,
+}
+
+text_columns!(TaxRecord { primary_ticket_no, ticket_no, coupon_no, nature_code, iso_code, is_refundable, amount_accounting_currency });
+text_columns!(DocumentLinkRecord {
+    primary_ticket_no,
+    ticket_no,
+    coupon_no,
+    document_status,
+    link_type,
+    linked_ticket_no,
+    linked_coupon_no,
+    linked_issue_date,
+});
+
+/// Counters collected while parsing, reported in the run summary
+#[derive(Clone, Debug, Default)]
+pub struct ParseStats {
+    /// FareDescription values with no configured target column, and how often they occurred
+    pub unknown_fare_types: BTreeMap<String, usize>,
+    /// records dropped by the configured filters, keyed by "field=value"
+    pub filtered: BTreeMap<String, usize>,
+    /// `"<profile> <version>"` -> files
+    pub feed_versions: BTreeMap<String, usize>,
+    pub warnings: Vec<String>,
+    pub control: ControlCounts,
+}
+
+impl ParseStats {
+    pub fn merge(&mut self, other: &ParseStats) {
+        for (fare_type, count) in &other.unknown_fare_types {
+            *self.unknown_fare_types.entry(fare_type.clone()).or_default() += count;
+        }
+        for (reason, count) in &other.filtered {
+            *self.filtered.entry(reason.clone()).or_default() += count;
+        }
+        for (version, count) in &other.feed_versions {
+            *self.feed_versions.entry(version.clone()).or_default() += count;
+        }
+        self.warnings.extend(other.warnings.iter().cloned());
+        self.control.merge(&other.control);
+    }
+}
+
+/// Element counts and amount sums taken while parsing, checked by reconcile.rs
+#[derive(Clone, Debug, Default)]
+pub struct ControlCounts {
+    pub transactions: u64,
+    pub documents: u64,
+    pub coupons: u64,
+    /// transactions kept as records, the others were filtered
+    pub records: u64,
+    /// ACCOUNTED coupon prorated fare amounts of every coupon
+    pub accounted_fare_amount: f64,
+    /// ACCOUNTED prorated fare of the last coupon of each transaction kept as a record,
+    /// the one its cpn_far_fare_amount_accounting_currency carries
+    pub kept_accounted_fare_amount: f64,
+    /// control totals the feed declares, metric -> value. They belong to one file,
+    /// merging keeps the later value.
+    pub declared: BTreeMap<String, f64>,
+}
+
+impl ControlCounts {
+    pub fn merge(&mut self, other: &ControlCounts) {
+        self.transactions += other.transactions;
+        self.documents += other.documents;
+        self.coupons += other.coupons;
+        self.records += other.records;
+        self.accounted_fare_amount += other.accounted_fare_amount;
+        self.kept_accounted_fare_amount += other.kept_accounted_fare_amount;
+        self.declared.extend(other.declared.iter().map(|(k, v)| (k.clone(), *v)));
+    }
+}
+
+/// Everything parse_xml extracts from one feed file
+#[derive(Clone, Debug, Default)]
+pub struct ParsedFeed {
+    pub records: Vec<Record>,
+    pub taxes: Vec<TaxRecord>,
+    pub links: Vec<DocumentLinkRecord>,
+    /// tax and link rows of every kept Transaction, one entry per record in record order
+    pub transactions: Vec<FeedRowCounts>,
+    pub stats: ParseStats,
+}
+
+/// Rows one kept Transaction added to the tax and link outputs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeedRowCounts {
+    pub taxes: usize,
+    pub links: usize,
+}
+
+impl ParsedFeed {
+    /// Takes the tax and link rows out of the feed, cut into one group per record
+    pub fn take_transaction_rows(&mut self) -> Vec<(Vec<TaxRecord>, Vec<DocumentLinkRecord>)> {
+        let mut taxes = std::mem::take(&mut self.taxes).into_iter();
+        let mut links = std::mem::take(&mut self.links).into_iter();
+        self.transactions
+            .iter()
+            .map(|counts| (taxes.by_ref().take(counts.taxes).collect(), links.by_ref().take(counts.links).collect()))
+            .collect()
+    }
+}
+
+// Normalized output: one struct per table. `*_id` columns are surrogate keys unique
+// within a run, the remaining identifiers are the natural keys from the feed.
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DocumentRow {
+    pub document_id: u64,
+    pub source_key: String,
+    pub primary_ticket_no: String,
     pub issue_date: String,
-    pub coupon_status: String,
-    pub segment: String,
-    pub flight_nr: String,
-    pub dep_date_time: String,
-    pub arr_date_time: String,
-    pub cabin: String,
-    pub rbd: String,
+    pub validating_carrier: String,
+    pub document_status: String,
+    pub currency: String,
+    pub tour_code: String,
+    pub pnr_no: String,
     pub pos: String,
     pub iata: String,
     pub distribution_channel: String,
-    pub fare_basis: String,
-    pub pnr_no: String,
-    pub revenue: String,
-    pub currency: String,
-    pub tour_code: String,
-    pub cpn_far_fare_amount_accounting_currency: String,
-    pub net_fare_amount_accounting_currency: String,
-    pub pub_fare_amount_accounting_currency: String,
-    pub bal_exchange_additional_collected_fare_amount_accounting_currency: String,
-    pub cpn_std_commission_amount_accounting_currency: String,
-    pub std_commission_amount_accounting_currency: String,
-    pub sup_commision_amount_accounting_currency: String,
-    pub sum_cpn_txo_tax_amount_accounting_currency: String,
-    pub cpn_txo_tax_amount_accounting_currency_yq: String,
-    pub exchange_rate: String,
-    pub document_status: String,
     pub trx_revenue_attributable_iata_number: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CouponRow {
+    pub coupon_id: u64,
+    pub document_id: u64,
+    pub primary_ticket_no: String,
+    pub ticket_no: String,
+    pub coupon_no: String,
+    pub coupon_status: String,
+    pub origin: String,
+    pub destination: String,
+    pub dep_date_time: String,
+    pub arr_date_time: String,
     pub marketting_carrier: String,
     pub operating_carrier: String,
-    pub validating_carrier: String,
+    pub flight_nr: String,
+    pub cabin: String,
+    pub rbd: String,
+    pub fare_basis: String,
+    pub prorated_fare_amount_accounting_currency: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct FareRow {
+    pub fare_id: u64,
+    pub document_id: u64,
+    pub primary_ticket_no: String,
+    pub fare_description: String,
+    pub amount_type: String,
+    pub amount: String,
+    pub roe: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaxRow {
+    pub tax_id: u64,
+    pub coupon_id: u64,
+    pub document_id: u64,
+    pub primary_ticket_no: String,
+    pub ticket_no: String,
+    pub coupon_no: String,
+    pub nature_code: String,
+    pub iso_code: String,
+    pub is_refundable: String,
+    pub amount_type: String,
+    pub amount: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CommissionRow {
+    pub commission_id: u64,
+    pub document_id: u64,
+    pub coupon_id: Option<u64>, // empty for document-level commissions
+    pub primary_ticket_no: String,
+    pub level: String,          // DOCUMENT or COUPON
+    pub commission_type: String, // STANDARD or SUPPLEMENTARY
+    pub amount_type: String,
+    pub amount: String,
+}
+
+// text columns only, surrogate keys cannot be rewritten
+text_columns!(DocumentRow {
+    source_key,
+    primary_ticket_no,
+    issue_date,
+    validating_carrier,
+    document_status,
+    currency,
+    tour_code,
+    pnr_no,
+    pos,
+    iata,
+    distribution_channel,
+    trx_revenue_attributable_iata_number,
+});
+text_columns!(CouponRow {
+    primary_ticket_no,
+    ticket_no,
+    coupon_no,
+    coupon_status,
+    origin,
+    destination,
+    dep_date_time,
+    arr_date_time,
+    marketting_carrier,
+    operating_carrier,
+    flight_nr,
+    cabin,
+    rbd,
+    fare_basis,
+    prorated_fare_amount_accounting_currency,
+});
+text_columns!(FareRow { primary_ticket_no, fare_description, amount_type, amount, roe });
+text_columns!(TaxRow { primary_ticket_no, ticket_no, coupon_no, nature_code, iso_code, is_refundable, amount_type, amount });
+text_columns!(CommissionRow { primary_ticket_no, level, commission_type, amount_type, amount });
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NormalizedFeed {
+    pub documents: Vec<DocumentRow>,
+    pub coupons: Vec<CouponRow>,
+    pub fares: Vec<FareRow>,
+    pub taxes: Vec<TaxRow>,
+    pub commissions: Vec<CommissionRow>,
+    /// rows of every kept Transaction, in the order of the record output
+    pub transactions: Vec<NormalizedRowCounts>,
+}
+
+/// Rows one kept Transaction added to each normalized table
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NormalizedRowCounts {
+    pub documents: usize,
+    pub coupons: usize,
+    pub fares: usize,
+    pub taxes: usize,
+    pub commissions: usize,
+}
+
+/// Side output rows of one Transaction, kept or dropped together with its Record
+/// by deduplication
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TransactionRows {
+    pub taxes: Vec<TaxRecord>,
+    pub links: Vec<DocumentLinkRecord>,
+    pub normalized: NormalizedFeed,
 }
@@ -0,0 +1,491 @@
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use std::io::BufRead;
+use anyhow::Result;
+
+use crate::diagnostics::ParseError;
+use crate::models::{CommissionRow, CouponRow, DocumentRow, FareRow, NormalizedFeed, NormalizedRowCounts, TaxRow};
+use crate::parser::{ParseOptions, get_attr_val, read_text};
+use crate::profiles::FeedPath;
+use crate::profiles::tags::*;
+
+/// Surrogate key counters, kept across files so ids stay unique within an output series
+#[derive(Debug, Default, Clone)]
+pub struct SurrogateKeys {
+    document: u64,
+    coupon: u64,
+    fare: u64,
+    tax: u64,
+    commission: u64,
+}
+
+impl SurrogateKeys {
+    // Counts up by the ids another counter set handed out
+    pub(crate) fn add(&mut self, other: &SurrogateKeys) {
+        self.document += other.document;
+        self.coupon += other.coupon;
+        self.fare += other.fare;
+        self.tax += other.tax;
+        self.commission += other.commission;
+    }
+}
+
+impl NormalizedFeed {
+    // Renumbers rows parsed with fresh counters as if they followed `keys`
+    pub(crate) fn shift_ids(&mut self, keys: &SurrogateKeys) {
+        // 0 marks a row outside any document or coupon and stays as it is
+        let shift = |id: &mut u64, by: u64| {
+            if *id != 0 {
+                *id += by;
+            }
+        };
+        for doc in &mut self.documents {
+            shift(&mut doc.document_id, keys.document);
+        }
+        for cpn in &mut self.coupons {
+            shift(&mut cpn.coupon_id, keys.coupon);
+            shift(&mut cpn.document_id, keys.document);
+        }
+        for fare in &mut self.fares {
+            shift(&mut fare.fare_id, keys.fare);
+            shift(&mut fare.document_id, keys.document);
+        }
+        for tax in &mut self.taxes {
+            shift(&mut tax.tax_id, keys.tax);
+            shift(&mut tax.coupon_id, keys.coupon);
+            shift(&mut tax.document_id, keys.document);
+        }
+        for comm in &mut self.commissions {
+            shift(&mut comm.commission_id, keys.commission);
+            shift(&mut comm.document_id, keys.document);
+            if let Some(coupon_id) = comm.coupon_id.as_mut() {
+                shift(coupon_id, keys.coupon);
+            }
+        }
+    }
+
+    pub(crate) fn append(&mut self, other: NormalizedFeed) {
+        self.documents.extend(other.documents);
+        self.coupons.extend(other.coupons);
+        self.fares.extend(other.fares);
+        self.taxes.extend(other.taxes);
+        self.commissions.extend(other.commissions);
+        self.transactions.extend(other.transactions);
+    }
+
+    /// Cuts the tables into one feed per kept Transaction, in record order
+    pub fn into_transactions(self) -> Vec<NormalizedFeed> {
+        let mut documents = self.documents.into_iter();
+        let mut coupons = self.coupons.into_iter();
+        let mut fares = self.fares.into_iter();
+        let mut taxes = self.taxes.into_iter();
+        let mut commissions = self.commissions.into_iter();
+        self.transactions
+            .into_iter()
+            .map(|counts| NormalizedFeed {
+                documents: documents.by_ref().take(counts.documents).collect(),
+                coupons: coupons.by_ref().take(counts.coupons).collect(),
+                fares: fares.by_ref().take(counts.fares).collect(),
+                taxes: taxes.by_ref().take(counts.taxes).collect(),
+                commissions: commissions.by_ref().take(counts.commissions).collect(),
+                transactions: vec![counts],
+            })
+            .collect()
+    }
+}
+
+fn next_id(counter: &mut u64) -> u64 {
+    *counter += 1;
+    *counter
+}
+
+// One AccountableEntity/Amount block: its type, amount and (for fares) rate of exchange
+#[derive(Debug, Default)]
+struct AmountBlock {
+    amount_type: String,
+    amount: String,
+    roe: String,
+}
+
+// Tax attributes kept while its amount blocks are read
+#[derive(Debug, Default)]
+struct TaxContext {
+    nature_code: String,
+    iso_code: String,
+    is_refundable: String,
+}
+
+/// Walks a feed file and keeps every Document, Coupon, Fare, Tax and Commission as its
+/// own row instead of folding them into a single flat Record. Transactions dropped by
+/// the record filters of `options` leave no rows and use up no ids.
+pub fn parse_normalized<R: BufRead>(
+    reader: &mut Reader<R>,
+    source_key: &str,
+    keys: &mut SurrogateKeys,
+    options: &ParseOptions,
+) -> Result<NormalizedFeed> {
+    let mut path = FeedPath::new(&options.profiles, options.version_policy);
+    normalize_events(reader, source_key, keys, &mut path, options)
+        .map_err(|e| ParseError::at(reader.buffer_position(), &path.names(), &e).into())
+}
+
+fn normalize_events<R: BufRead>(
+    reader: &mut Reader<R>,
+    source_key: &str,
+    keys: &mut SurrogateKeys,
+    path: &mut FeedPath,
+    options: &ParseOptions,
+) -> Result<NormalizedFeed> {
+    let mut buf = Vec::new();
+    // read_text needs its own buffer while the event in `buf` is still borrowed
+    let mut text_buf = Vec::new();
+    let mut out = NormalizedFeed::default();
+
+    let mut document: Option<DocumentRow> = None;
+    let mut coupon: Option<CouponRow> = None;
+    let mut fare_description = String::new();
+    let mut tax: Option<TaxContext> = None;
+    let mut amount: Option<AmountBlock> = None;
+
+    // first row index of the current transaction/document, used to back-fill values
+    // that the feed only gives after the rows were started
+    let mut trx_document_start = 0usize;
+    let mut trx_coupon_start = 0usize;
+    let mut trx_fare_start = 0usize;
+    let mut trx_tax_start = 0usize;
+    let mut trx_commission_start = 0usize;
+    let mut trx_keys = keys.clone();
+    let mut event_type = String::new();
+    let mut doc_fare_start = 0usize;
+    let mut doc_commission_start = 0usize;
+    let mut document_status = String::new();
+
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                path.open(&e)?;
+
+                let path_ref = path.segments();
+
+                match path_ref {
+                    [AMA_REV_FEED, TRANSACTION] => {
+                        trx_document_start = out.documents.len();
+                        trx_coupon_start = out.coupons.len();
+                        trx_fare_start = out.fares.len();
+                        trx_tax_start = out.taxes.len();
+                        trx_commission_start = out.commissions.len();
+                        trx_keys = keys.clone();
+                        event_type.clear();
+                        document_status.clear();
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, EVENT] => {
+                        event_type = get_attr_val(&e, b"Type");
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, EVENT, ENTITY_STATUS] => {
+                        document_status = read_text(reader, &mut text_buf)?;
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT] => {
+                        doc_fare_start = out.fares.len();
+                        doc_commission_start = out.commissions.len();
+                        document = Some(DocumentRow {
+                            document_id: next_id(&mut keys.document),
+                            source_key: source_key.to_string(),
+                            issue_date: get_attr_val(&e, b"DateOfIssuance"),
+                            validating_carrier: get_attr_val(&e, b"ValidatingCarrier"),
+                            ..Default::default()
+                        });
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, PRICING_DETAILS, CURRENCY_OF_PAYMENT] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(doc) = document.as_mut() {
+                            doc.currency = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, PRICING_DETAILS, TOUR_CODE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(doc) = document.as_mut() {
+                            doc.tour_code = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, BOOKING_INFORMATION, PNR_IDENTIFICATION, AMADEUS_RECORD_LOCATOR, ID] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(doc) = document.as_mut() {
+                            doc.pnr_no = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON] => {
+                        let document_id = document.as_ref().map(|d| d.document_id).unwrap_or_default();
+                        let primary_ticket_no = get_attr_val(&e, b"DocumentNbr");
+
+                        // the document number is only carried by its coupons
+                        if let Some(doc) = document.as_mut()
+                            && doc.primary_ticket_no.is_empty()
+                        {
+                            doc.primary_ticket_no = primary_ticket_no.clone();
+                        }
+
+                        coupon = Some(CouponRow {
+                            coupon_id: next_id(&mut keys.coupon),
+                            document_id,
+                            primary_ticket_no,
+                            ticket_no: get_attr_val(&e, b"ConjunctiveDocumentNbr"),
+                            coupon_no: get_attr_val(&e, b"Number"),
+                            coupon_status: get_attr_val(&e, b"Status"),
+                            ..Default::default()
+                        });
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO] => {
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.origin = get_attr_val(&e, b"OriginAirportCode");
+                            cpn.destination = get_attr_val(&e, b"DestinationAirportCode");
+                            cpn.dep_date_time = get_attr_val(&e, b"DepartureDate");
+                            cpn.arr_date_time = get_attr_val(&e, b"ArrivalDate");
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, COMPANY_DETAILS, MARKETING_CARRIER] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.marketting_carrier = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, COMPANY_DETAILS, OPERATING_CARRIER] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.operating_carrier = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, CLASS_DETAILS, BOOKING_CLASS] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.rbd = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, CLASS_DETAILS, OPERATING_CABIN_CLASS] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.cabin = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, FLIGHT_IDENTIFICATION, OPERATING_FLIGHT_NUMBER, FLIGHT_NUMBER] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.flight_nr = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, COUPON_DETAILS, FARE_BASIS_CODE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(cpn) = coupon.as_mut() {
+                            cpn.fare_basis = txt;
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, FARES, FARE] => {
+                        fare_description = get_attr_val(&e, b"FareDescription");
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_TAXES, COLLECTED_TAXES_CPN_LVL, TAX] => {
+                        tax = Some(TaxContext {
+                            nature_code: get_attr_val(&e, b"NatureCode"),
+                            iso_code: get_attr_val(&e, b"ISOCode"),
+                            is_refundable: get_attr_val(&e, b"IsRefundable"),
+                        });
+                    }
+
+                    [.., ACCOUNTABLE_ENTITY, AMOUNT] => {
+                        amount = Some(AmountBlock::default());
+                    }
+
+                    [.., ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(block) = amount.as_mut() {
+                            block.amount_type = txt;
+                        }
+                    }
+
+                    [.., ACCOUNTABLE_ENTITY, AMOUNT, ROE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if let Some(block) = amount.as_mut() {
+                            block.roe = txt;
+                        }
+                    }
+
+                    _ => {}
+                }
+            }
+
+            Event::Empty(e) => {
+                path.open(&e)?;
+
+                let path_ref = path.segments();
+
+                match path_ref {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, ISSUANCE_DETAILS] => {
+                        if let Some(doc) = document.as_mut() {
+                            doc.pos = get_attr_val(&e, b"CityPOS");
+                            doc.iata = get_attr_val(&e, b"Iata");
+                            doc.distribution_channel = get_attr_val(&e, b"OfficeId");
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, PRICING_DETAILS, REVENUE_ATTRIBUTABLE_AGENT] => {
+                        if let Some(doc) = document.as_mut() {
+                            doc.trx_revenue_attributable_iata_number = get_attr_val(&e, b"AgencyNumber");
+                        }
+                    }
+
+                    [.., ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT] => {
+                        if let Some(block) = amount.as_mut() {
+                            block.amount = get_attr_val(&e, b"Amount");
+                        }
+                    }
+
+                    _ => {}
+                }
+
+                path.close();
+            }
+
+            Event::End(_) => {
+                let path_ref = path.segments();
+
+                match path_ref {
+                    [.., ACCOUNTABLE_ENTITY, AMOUNT] => {
+                        if let Some(block) = amount.take() {
+                            let document_id = document.as_ref().map(|d| d.document_id).unwrap_or_default();
+                            let owner = &path_ref[..path_ref.len() - 2];
+
+                            if owner.contains(&FARE) {
+                                out.fares.push(FareRow {
+                                    fare_id: next_id(&mut keys.fare),
+                                    document_id,
+                                    primary_ticket_no: String::new(),
+                                    fare_description: fare_description.clone(),
+                                    amount_type: block.amount_type,
+                                    amount: block.amount,
+                                    roe: block.roe,
+                                });
+                            } else if owner.contains(&COLLECTED_TAXES_CPN_LVL) {
+                                if let (Some(cpn), Some(t)) = (coupon.as_ref(), tax.as_ref()) {
+                                    out.taxes.push(TaxRow {
+                                        tax_id: next_id(&mut keys.tax),
+                                        coupon_id: cpn.coupon_id,
+                                        document_id,
+                                        primary_ticket_no: cpn.primary_ticket_no.clone(),
+                                        ticket_no: cpn.ticket_no.clone(),
+                                        coupon_no: cpn.coupon_no.clone(),
+                                        nature_code: t.nature_code.clone(),
+                                        iso_code: t.iso_code.clone(),
+                                        is_refundable: t.is_refundable.clone(),
+                                        amount_type: block.amount_type,
+                                        amount: block.amount,
+                                    });
+                                }
+                            } else if owner.contains(&COUPON_PRORATED_FARE) {
+                                if let Some(cpn) = coupon.as_mut()
+                                    && block.amount_type == "ACCOUNTED"
+                                {
+                                    cpn.prorated_fare_amount_accounting_currency = block.amount;
+                                }
+                            } else if owner.contains(&COMMISSION) {
+                                let (level, commission_type, coupon_id) = if owner.contains(&COUPON_STANDARD_COMMISSION) {
+                                    ("COUPON", "STANDARD", coupon.as_ref().map(|c| c.coupon_id))
+                                } else if owner.contains(&SUPPLEMENTARY_COMMISSION) {
+                                    ("DOCUMENT", "SUPPLEMENTARY", None)
+                                } else {
+                                    ("DOCUMENT", "STANDARD", None)
+                                };
+
+                                out.commissions.push(CommissionRow {
+                                    commission_id: next_id(&mut keys.commission),
+                                    document_id,
+                                    coupon_id,
+                                    primary_ticket_no: String::new(),
+                                    level: level.to_string(),
+                                    commission_type: commission_type.to_string(),
+                                    amount_type: block.amount_type,
+                                    amount: block.amount,
+                                });
+                            }
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_TAXES, COLLECTED_TAXES_CPN_LVL, TAX] => {
+                        tax = None;
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON] => {
+                        if let Some(cpn) = coupon.take() {
+                            out.coupons.push(cpn);
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT] => {
+                        if let Some(doc) = document.take() {
+                            for fare in &mut out.fares[doc_fare_start..] {
+                                fare.primary_ticket_no = doc.primary_ticket_no.clone();
+                            }
+                            for comm in &mut out.commissions[doc_commission_start..] {
+                                comm.primary_ticket_no = doc.primary_ticket_no.clone();
+                            }
+                            out.documents.push(doc);
+                        }
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION] => {
+                        // the event status may come after the documents it applies to
+                        for doc in &mut out.documents[trx_document_start..] {
+                            doc.document_status = document_status.clone();
+                        }
+
+                        // same filters as the record output, which sees the last coupon's status
+                        let coupon_status = out.coupons[trx_coupon_start..]
+                            .last()
+                            .map(|c| c.coupon_status.as_str())
+                            .unwrap_or_default();
+                        if options.rejection(&event_type, &document_status, coupon_status).is_some() {
+                            out.documents.truncate(trx_document_start);
+                            out.coupons.truncate(trx_coupon_start);
+                            out.fares.truncate(trx_fare_start);
+                            out.taxes.truncate(trx_tax_start);
+                            out.commissions.truncate(trx_commission_start);
+                            *keys = trx_keys.clone();
+                        } else {
+                            out.transactions.push(NormalizedRowCounts {
+                                documents: out.documents.len() - trx_document_start,
+                                coupons: out.coupons.len() - trx_coupon_start,
+                                fares: out.fares.len() - trx_fare_start,
+                                taxes: out.taxes.len() - trx_tax_start,
+                                commissions: out.commissions.len() - trx_commission_start,
+                            });
+                        }
+                    }
+
+                    _ => {}
+                }
+
+                path.close();
+            }
+
+            Event::Eof => break,
+            _ => {}
+        }
+
+        buf.clear();
+    }
+
+    Ok(out)
+}
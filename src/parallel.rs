@@ -0,0 +1,188 @@
+use anyhow::Result;
+use memchr::memmem;
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use rayon::prelude::*;
+use std::io::{Chain, Cursor, Read};
+
+use crate::models::{NormalizedFeed, ParsedFeed};
+use crate::normalized::{SurrogateKeys, parse_normalized};
+use crate::parser::{ParseOptions, finish_feed, parse_transactions, parse_xml_with_options};
+use crate::profiles::{FeedPath, MandatoryPaths};
+
+/// Parses one in-memory feed file on the rayon pool. The file is cut into slices of
+/// about `chunk_bytes` at top-level `<Transaction>` starts; every slice is wrapped in
+/// the root element and goes through the sequential state machine, and the results
+/// are concatenated in source order. Files that cannot be cut safely (a single slice,
+/// wrapper elements between root and Transaction) are parsed sequentially, and so
+/// are files where any slice fails.
+pub fn parse_xml_parallel(bytes: &[u8], options: &ParseOptions, chunk_bytes: usize) -> Result<ParsedFeed> {
+    let parts = parse_slices(bytes, options, chunk_bytes, |reader| {
+        let mut path = FeedPath::new(&options.profiles, options.version_policy);
+        let mut mandatory = MandatoryPaths::new(&options.mandatory_paths);
+        parse_transactions(reader, options, &mut path, &mut mandatory).map(|feed| (feed, path, mandatory))
+    });
+    let Some(parts) = parts else {
+        return parse_sequential(bytes, options);
+    };
+
+    let mut feed = ParsedFeed::default();
+    let mut first: Option<(FeedPath, MandatoryPaths)> = None;
+    for (part, path, part_mandatory) in parts {
+        feed.records.extend(part.records);
+        feed.taxes.extend(part.taxes);
+        feed.links.extend(part.links);
+        feed.transactions.extend(part.transactions);
+        feed.stats.merge(&part.stats);
+
+        match first.as_mut() {
+            Some((_, mandatory)) => mandatory.merge(&part_mandatory),
+            None => first = Some((path, part_mandatory)),
+        }
+    }
+
+    // version and profile warnings are the same in every slice, the first one reports them
+    if let Some((mut path, mandatory)) = first {
+        finish_feed(&mut feed.stats, &mut path, &mandatory, options)?;
+    }
+    Ok(feed)
+}
+
+/// Normalized tables of one in-memory feed file, cut into slices like
+/// [`parse_xml_parallel`]. Every slice numbers its rows from zero; the ids are shifted
+/// by the rows of the slices before it, so they come out as in a sequential pass.
+pub fn parse_normalized_parallel(
+    bytes: &[u8],
+    source_key: &str,
+    keys: &mut SurrogateKeys,
+    options: &ParseOptions,
+    chunk_bytes: usize,
+) -> Result<NormalizedFeed> {
+    let parts = parse_slices(bytes, options, chunk_bytes, |reader| {
+        let mut slice_keys = SurrogateKeys::default();
+        parse_normalized(reader, source_key, &mut slice_keys, options).map(|feed| (feed, slice_keys))
+    });
+    let Some(parts) = parts else {
+        let mut reader = Reader::from_reader(Cursor::new(bytes));
+        reader.trim_text(true);
+        return parse_normalized(&mut reader, source_key, keys, options);
+    };
+
+    let mut feed = NormalizedFeed::default();
+    for (mut part, slice_keys) in parts {
+        part.shift_ids(keys);
+        keys.add(&slice_keys);
+        feed.append(part);
+    }
+    Ok(feed)
+}
+
+fn parse_sequential(bytes: &[u8], options: &ParseOptions) -> Result<ParsedFeed> {
+    let mut reader = Reader::from_reader(Cursor::new(bytes));
+    reader.trim_text(true);
+    parse_xml_with_options(&mut reader, options)
+}
+
+type SliceReader<'a> = Reader<Chain<Chain<&'a [u8], &'a [u8]>, &'a [u8]>>;
+
+// Runs `parse` over every slice on the rayon pool, in source order. None when the file
+// cannot be cut safely or any slice fails.
+fn parse_slices<T, F>(bytes: &[u8], options: &ParseOptions, chunk_bytes: usize, parse: F) -> Option<Vec<T>>
+where
+    T: Send,
+    F: Fn(&mut SliceReader) -> Result<T> + Sync,
+{
+    let root = root_element(bytes, options)?;
+
+    let bounds = slice_bounds(bytes, root.body_start, chunk_bytes);
+    if bounds.len() < 3 {
+        return None;
+    }
+
+    let prefix = &bytes[..root.body_start];
+    let last = bounds.len() - 2;
+
+    let results: Vec<Result<T>> = bounds
+        .par_windows(2)
+        .enumerate()
+        .map(|(i, window)| {
+            let (start, end) = (window[0], window[1]);
+            // the first slice carries the real root start tag, the last one the real end tag
+            let head: &[u8] = if i == 0 { b"" } else { prefix };
+            let tail: &[u8] = if i == last { b"" } else { root.end_tag.as_bytes() };
+
+            let mut reader = Reader::from_reader(Read::chain(head, &bytes[start..end]).chain(tail));
+            reader.trim_text(true);
+            parse(&mut reader)
+        })
+        .collect();
+
+    // a slice that does not parse means the cut was not where it should have been
+    // (a Transaction nested in an unknown element, a boundary inside a comment or
+    // CDATA) or the file is broken; the sequential pass gives the authoritative result
+    results.into_iter().collect::<Result<Vec<_>>>().ok()
+}
+
+struct RootElement {
+    // offset right after the root start tag
+    body_start: usize,
+    end_tag: String,
+}
+
+// The root start tag, unless the file has none or its profiles use wrapper elements
+fn root_element(bytes: &[u8], options: &ParseOptions) -> Option<RootElement> {
+    let mut reader = Reader::from_reader(bytes);
+    loop {
+        match reader.read_event().ok()? {
+            Event::Start(e) => {
+                let local_name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                let has_wrappers = options
+                    .profiles
+                    .iter()
+                    .any(|p| p.root == local_name && !p.wrappers.is_empty());
+                if has_wrappers {
+                    return None;
+                }
+                return Some(RootElement {
+                    body_start: reader.buffer_position(),
+                    end_tag: format!("</{}>", String::from_utf8_lossy(e.name().as_ref())),
+                });
+            }
+            Event::Empty(_) | Event::Eof => return None,
+            _ => {}
+        }
+    }
+}
+
+// Slice boundaries: 0, the <Transaction> starts found after every `chunk_bytes`, and
+// the file length. Transaction only occurs directly below the root, so a match is
+// a top-level boundary.
+fn slice_bounds(bytes: &[u8], body_start: usize, chunk_bytes: usize) -> Vec<usize> {
+    let finder = memmem::Finder::new(b"<Transaction");
+    let mut bounds = vec![0];
+
+    let mut pos = body_start + chunk_bytes.max(1);
+    while pos < bytes.len() {
+        let Some(found) = next_transaction(bytes, pos, &finder) else {
+            break;
+        };
+        bounds.push(found);
+        pos = found + chunk_bytes.max(1);
+    }
+
+    bounds.push(bytes.len());
+    bounds
+}
+
+fn next_transaction(bytes: &[u8], from: usize, finder: &memmem::Finder) -> Option<usize> {
+    let mut pos = from;
+    loop {
+        let found = pos + finder.find(&bytes[pos..])?;
+        // skip longer names such as <Transactions>
+        match bytes.get(found + finder.needle().len()) {
+            Some(b'>' | b'/') => return Some(found),
+            Some(b) if b.is_ascii_whitespace() => return Some(found),
+            _ => pos = found + 1,
+        }
+    }
+}
@@ -1,171 +1,409 @@
 use quick_xml::Reader;
 use quick_xml::events::{BytesStart, Event};
 use std::io::BufRead;
-use anyhow::Result;
+use anyhow::{Result, bail};
+
+use crate::config;
+use crate::diagnostics::ParseError;
+use crate::profiles::tags::*;
+use crate::profiles::{FeedPath, FeedProfile, MandatoryPaths, Strictness};
+use crate::models::{ControlCounts, DocumentLinkRecord, FareColumn, FeedRowCounts, ParseStats, ParsedFeed, Record, TaxRecord};
+use crate::reconcile::ControlTotal;
+
+/// Settings that change what parse_xml extracts, defaults come from config.rs
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// AmountType whose fare amounts and ROE are captured
+    pub fare_amount_type: String,
+    /// FareDescription -> target column
+    pub fare_columns: Vec<(String, FareColumn)>,
+    /// applies to whole transactions, by the status of their last coupon (the one the
+    /// Record carries); the statuses of earlier coupons are not looked at
+    pub coupon_status: ValueFilter,
+    pub document_status: ValueFilter,
+    pub event_type: ValueFilter,
+    /// mapping profiles, picked by the root element of each file
+    pub profiles: Vec<FeedProfile>,
+    pub version_policy: Strictness,
+    /// paths below the root every file must contain, e.g. "Transaction/Document"
+    pub mandatory_paths: Vec<String>,
+    pub mandatory_policy: Strictness,
+    /// control totals read from the feed for reconciliation
+    pub control_totals: Vec<ControlTotal>,
+}
 
-use crate::models::Record;
+/// Include/exclude lists for one field; an empty include list keeps every value
+#[derive(Clone, Debug, Default)]
+pub struct ValueFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
 
-pub fn parse_xml<R: BufRead>(reader: &mut Reader<R>) -> Result<Vec<Record>> {
-    let mut buf = Vec::new();
-    let mut path: Vec<String> = Vec::new();
+impl ValueFilter {
+    pub fn new(include: &[&str], exclude: &[&str]) -> Self {
+        Self {
+            include: include.iter().map(|s| s.to_string()).collect(),
+            exclude: exclude.iter().map(|s| s.to_string()).collect(),
+        }
+    }
 
-    let mut rows: Vec<Record> = Vec::new();
+    pub fn keeps(&self, value: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|v| v == value))
+            && !self.exclude.iter().any(|v| v == value)
+    }
+}
 
-    let mut rec = Record::default();
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            fare_amount_type: config::FARE_AMOUNT_TYPE.to_string(),
+            fare_columns: config::FARE_COLUMNS
+                .iter()
+                .map(|(desc, col)| (desc.to_string(), *col))
+                .collect(),
+            coupon_status: ValueFilter::new(config::COUPON_STATUS_INCLUDE, config::COUPON_STATUS_EXCLUDE),
+            document_status: ValueFilter::new(config::DOCUMENT_STATUS_INCLUDE, config::DOCUMENT_STATUS_EXCLUDE),
+            event_type: ValueFilter::new(config::EVENT_TYPE_INCLUDE, config::EVENT_TYPE_EXCLUDE),
+            profiles: config::FEED_PROFILES.to_vec(),
+            version_policy: config::UNSUPPORTED_VERSION_POLICY,
+            mandatory_paths: config::MANDATORY_PATHS.iter().map(|p| p.to_string()).collect(),
+            mandatory_policy: config::MISSING_PATH_POLICY,
+            control_totals: config::CONTROL_TOTALS.to_vec(),
+        }
+    }
+}
 
-    let mut last_fare_type = String::new();
+impl ParseOptions {
+    fn fare_column(&self, fare_description: &str) -> Option<FareColumn> {
+        self.fare_columns
+            .iter()
+            .find(|(desc, _)| desc == fare_description)
+            .map(|(_, col)| *col)
+    }
+
+    // First filter that drops the transaction, as "field=value". The coupon status is
+    // that of the transaction's last coupon, the one its Record carries.
+    pub(crate) fn rejection(&self, event_type: &str, document_status: &str, coupon_status: &str) -> Option<String> {
+        if !self.event_type.keeps(event_type) {
+            return Some(format!("event_type={}", event_type));
+        }
+        if !self.document_status.keeps(document_status) {
+            return Some(format!("document_status={}", document_status));
+        }
+        if !self.coupon_status.keeps(coupon_status) {
+            return Some(format!("coupon_status={}", coupon_status));
+        }
+        None
+    }
+}
 
-    let mut total_cpn_amount: f64 = 0.0;
-    let mut temp_cpn_amount: f64 = 0.0;
-    let mut temp_tax_amount: f64 = 0.0;
+pub fn parse_xml<R: BufRead>(reader: &mut Reader<R>) -> Result<ParsedFeed> {
+    parse_xml_with_options(reader, &ParseOptions::default())
+}
+
+pub fn parse_xml_with_options<R: BufRead>(reader: &mut Reader<R>, options: &ParseOptions) -> Result<ParsedFeed> {
+    let mut path = FeedPath::new(&options.profiles, options.version_policy);
+    let mut mandatory = MandatoryPaths::new(&options.mandatory_paths);
+
+    let mut feed = parse_transactions(reader, options, &mut path, &mut mandatory)?;
+    finish_feed(&mut feed.stats, &mut path, &mandatory, options)?;
+    Ok(feed)
+}
+
+/// Runs the state machine over one document, a whole file or a slice of its
+/// Transactions. Failures come back as a ParseError with the byte offset and element path.
+pub fn parse_transactions<R: BufRead>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    path: &mut FeedPath,
+    mandatory: &mut MandatoryPaths,
+) -> Result<ParsedFeed> {
+    let mut records = Vec::new();
+    let mut feed = parse_each(reader, options, path, mandatory, &mut |rec| {
+        records.push(rec);
+        Ok(())
+    })?;
+    feed.records = records;
+    Ok(feed)
+}
+
+/// Like parse_xml_with_options, but every kept Record is handed to `on_record` as soon
+/// as its Transaction ends instead of being collected; the returned feed has no
+/// records, only taxes, links and stats. An error from `on_record` stops the parse.
+pub fn parse_xml_each<R: BufRead>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    mut on_record: impl FnMut(Record) -> Result<()>,
+) -> Result<ParsedFeed> {
+    let mut path = FeedPath::new(&options.profiles, options.version_policy);
+    let mut mandatory = MandatoryPaths::new(&options.mandatory_paths);
+
+    let mut feed = parse_each(reader, options, &mut path, &mut mandatory, &mut on_record)?;
+    finish_feed(&mut feed.stats, &mut path, &mandatory, options)?;
+    Ok(feed)
+}
+
+fn parse_each<R: BufRead>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    path: &mut FeedPath,
+    mandatory: &mut MandatoryPaths,
+    on_record: &mut dyn FnMut(Record) -> Result<()>,
+) -> Result<ParsedFeed> {
+    parse_events(reader, options, path, mandatory, on_record)
+        .map_err(|e| ParseError::at(reader.buffer_position(), &path.names(), &e).into())
+}
+
+/// File-level checks once every Transaction was read: mandatory paths and feed version
+pub fn finish_feed(
+    stats: &mut ParseStats,
+    path: &mut FeedPath,
+    mandatory: &MandatoryPaths,
+    options: &ParseOptions,
+) -> Result<()> {
+    let missing = mandatory.missing();
+    if !missing.is_empty() {
+        let msg = format!("mandatory paths never seen: {}", missing.join(", "));
+        if options.mandatory_policy == Strictness::Fail {
+            bail!(msg);
+        }
+        stats.warnings.push(msg);
+    }
+
+    stats.warnings.extend(path.take_warnings());
+    let version = path.detected().unwrap_or_else(|| "unknown".to_string());
+    *stats.feed_versions.entry(version).or_default() += 1;
+    Ok(())
+}
+
+// Parser state that belongs to one Transaction. It starts fresh with every
+// Transaction, so a file parsed in slices gives the same output as in one pass.
+#[derive(Default)]
+struct TransactionState {
+    last_fare_column: Option<FareColumn>,
+    event_type: String,
+
+    // document reference being read and how many coupon references it carried
+    current_link: Option<DocumentLinkRecord>,
+    current_link_coupons: usize,
+
+    // coupon taxes of this transaction, its record's sum_cpn_txo_tax_amount_accounting_currency
+    total_cpn_amount: f64,
+    // ACCOUNTED prorated fare of the last coupon, the one the record carries
+    last_accounted_fare: f64,
+    temp_cpn_amount: f64,
+    temp_tax_amount: f64,
+
+    // coupon-level tax currently being read, emitted once its accounted amount is seen
+    current_tax: Option<TaxRecord>,
 
     // STATE FLAGS
-    let mut in_coup_standard_comm_amounts_1 = false;
-    let mut in_coup_standard_comm_amounts_2 = false;
-    let mut in_calculated_amounts = false;
-    let mut in_pricing_fares = false;
-    let mut wait_for_cpn_lvl = false;
-    let mut waiting_for_amount_fare = false;
-    let mut waiting_for_coup_standard_comm_amount = false;
-    let mut waiting_for_std_comm_amount = false;
-    let mut waiting_for_supp_comm_amount = false;
-    let mut waiting_for_amount_proratedfare = false;
-    let mut wait_for_cpn_lvl_accounted = false;
-    let mut waiting_for_amount_fare_roe = false;
+    in_coup_standard_comm_amounts_1: bool,
+    in_coup_standard_comm_amounts_2: bool,
+    in_calculated_amounts: bool,
+    in_pricing_fares: bool,
+    wait_for_cpn_lvl: bool,
+    waiting_for_amount_fare: bool,
+    waiting_for_coup_standard_comm_amount: bool,
+    waiting_for_std_comm_amount: bool,
+    waiting_for_supp_comm_amount: bool,
+    waiting_for_amount_proratedfare: bool,
+    wait_for_cpn_lvl_accounted: bool,
+    waiting_for_amount_fare_roe: bool,
+    waiting_for_tax_amount: bool,
+}
+
+fn parse_events<R: BufRead>(
+    reader: &mut Reader<R>,
+    options: &ParseOptions,
+    path: &mut FeedPath,
+    mandatory: &mut MandatoryPaths,
+    on_record: &mut dyn FnMut(Record) -> Result<()>,
+) -> Result<ParsedFeed> {
+    let mut buf = Vec::new();
+    // read_text needs its own buffer while the event in `buf` is still borrowed
+    let mut text_buf = Vec::new();
+
+    let mut taxes: Vec<TaxRecord> = Vec::new();
+    let mut links: Vec<DocumentLinkRecord> = Vec::new();
+    let mut transactions: Vec<FeedRowCounts> = Vec::new();
+    let mut stats = ParseStats::default();
+
+    let mut rec = Record::default();
+
+    // index of the first tax/link of the current transaction, dropped with it when filtered
+    let mut trx_tax_start = 0usize;
+    let mut trx_link_start = 0usize;
+    let mut trx = TransactionState::default();
 
     loop {
         match reader.read_event_into(&mut buf)? {
             Event::Start(e) => {
-                let tag = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
-                path.push(tag.clone());
+                path.open(&e)?;
+
+                mandatory.observe(path);
+                observe_control(&mut stats.control, path, &e, options);
+                let path_ref = path.segments();
+
+                match path_ref {
+                    [AMA_REV_FEED, TRANSACTION] => {
+                        // transactions are independent, so that slices of a file can be parsed apart
+                        trx = TransactionState::default();
+                        rec = Record::default();
+                        trx_tax_start = taxes.len();
+                        trx_link_start = links.len();
+                    }
 
-                let path_ref: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, REFERENCED_DOCUMENTS, REFERENCED_DOCUMENT] => {
+                        trx.current_link = Some(document_link(&e, &rec));
+                        trx.current_link_coupons = 0;
+                    }
 
-                match path_ref.as_slice() {
-                    ["AMA_REV.Feed", "Transaction", "Document"] => {
+                    [AMA_REV_FEED, TRANSACTION, EVENT] => {
+                        trx.event_type = get_attr_val(&e, b"Type");
+                        rec.transaction_timestamp = get_attr_val(&e, b"Timestamp");
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT] => {
                         rec.issue_date = get_attr_val(&e, b"DateOfIssuance");
                         rec.validating_carrier = get_attr_val(&e, b"ValidatingCarrier");
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Event", "EntityStatus"] => {
-                        rec.document_status = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, EVENT, ENTITY_STATUS] => {
+                        rec.document_status = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "PricingDetails", "CurrencyOfPayment"] => {
-                        rec.currency = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, PRICING_DETAILS, CURRENCY_OF_PAYMENT] => {
+                        rec.currency = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "PricingDetails", "TourCode"] => {
-                        rec.tour_code = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, PRICING_DETAILS, TOUR_CODE] => {
+                        rec.tour_code = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "BookingInformation", "PNRIdentification", "AmadeusRecordLocator", "ID"] => {
-                        rec.pnr_no = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, BOOKING_INFORMATION, PNR_IDENTIFICATION, AMADEUS_RECORD_LOCATOR, ID] => {
+                        rec.pnr_no = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon"] => {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON] => {
                         rec.primary_ticket_no = get_attr_val(&e, b"DocumentNbr");
                         rec.ticket_no = get_attr_val(&e, b"ConjunctiveDocumentNbr");
                         rec.coupon_no = get_attr_val(&e, b"Number");
                         rec.coupon_status = get_attr_val(&e, b"Status");
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "SegmentInfo", "CompanyDetails", "MarketingCarrier"] => {
-                        rec.marketting_carrier = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, COMPANY_DETAILS, MARKETING_CARRIER] => {
+                        rec.marketting_carrier = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "SegmentInfo", "CompanyDetails", "OperatingCarrier"] => {
-                        rec.operating_carrier = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, COMPANY_DETAILS, OPERATING_CARRIER] => {
+                        rec.operating_carrier = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CouponDetails", "FareBasisCode"] => {
-                        rec.fare_basis = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, COUPON_DETAILS, FARE_BASIS_CODE] => {
+                        rec.fare_basis = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Fares", "Fare"] => {
-                        in_pricing_fares = true;
-                        last_fare_type = get_attr_val(&e, b"FareDescription");
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, FARES, FARE] => {
+                        trx.in_pricing_fares = true;
+                        let fare_type = get_attr_val(&e, b"FareDescription");
+                        trx.last_fare_column = options.fare_column(&fare_type);
+                        if trx.last_fare_column.is_none() {
+                            *stats.unknown_fare_types.entry(fare_type).or_default() += 1;
+                        }
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Fares", "Fare", "AccountableEntity", "Amount", "AmountType"] => {
-                        let txt = read_text(reader)?;
-                        if txt == "ACCOUNTED" {
-                            waiting_for_amount_fare = true;
-                            waiting_for_amount_fare_roe = true;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, FARES, FARE, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
+                        if txt == options.fare_amount_type {
+                            trx.waiting_for_amount_fare = true;
+                            trx.waiting_for_amount_fare_roe = true;
                         }
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Fares", "Fare", "AccountableEntity", "Amount", "ROE"] 
-                        if in_pricing_fares && waiting_for_amount_fare_roe => {
-                            rec.exchange_rate = read_text(reader)?;
-                            waiting_for_amount_fare_roe = false;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, FARES, FARE, ACCOUNTABLE_ENTITY, AMOUNT, ROE] 
+                        if trx.in_pricing_fares && trx.waiting_for_amount_fare_roe => {
+                            rec.exchange_rate = read_text(reader, &mut text_buf)?;
+                            trx.waiting_for_amount_fare_roe = false;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponStandardCommission"] => {
-                        in_coup_standard_comm_amounts_1 = true;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_STANDARD_COMMISSION] => {
+                        trx.in_coup_standard_comm_amounts_1 = true;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponStandardCommission", "Commission"] => {
-                        in_coup_standard_comm_amounts_2 = true;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_STANDARD_COMMISSION, COMMISSION] => {
+                        trx.in_coup_standard_comm_amounts_2 = true;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponStandardCommission", "Commission", "AccountableEntity", "Amount", "AmountType"]
-                        if in_coup_standard_comm_amounts_1 && in_coup_standard_comm_amounts_2 =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_STANDARD_COMMISSION, COMMISSION, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE]
+                        if trx.in_coup_standard_comm_amounts_1 && trx.in_coup_standard_comm_amounts_2 =>
                     {
-                        let txt = read_text(reader)?;
+                        let txt = read_text(reader, &mut text_buf)?;
                         if txt == "ACCOUNTED" {
-                            waiting_for_coup_standard_comm_amount = true;
+                            trx.waiting_for_coup_standard_comm_amount = true;
                         }
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts"] => {
-                        in_calculated_amounts = true;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS] => {
+                        trx.in_calculated_amounts = true;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponProratedFare", "AccountableEntity", "Amount", "AmountType"]
-                        if in_calculated_amounts =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_PRORATED_FARE, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE]
+                        if trx.in_calculated_amounts =>
                     {
-                        let txt = read_text(reader)?;
+                        let txt = read_text(reader, &mut text_buf)?;
                         if txt == "ACCOUNTED" {
-                            waiting_for_amount_proratedfare = true;
+                            trx.waiting_for_amount_proratedfare = true;
                         }
                     }
 
                     // for revenue 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponTaxes", "CollectedTaxesCpnLvl", "Tax"] => {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_TAXES, COLLECTED_TAXES_CPN_LVL, TAX] => {
                             let nature_code = get_attr_val(&e, b"NatureCode");
                             let iso_code = get_attr_val(&e, b"ISOCode");
                             let is_refundable =  get_attr_val(&e, b"IsRefundable");
                             if nature_code == "AC" && iso_code == "YQ" && is_refundable == "N" {
-                                wait_for_cpn_lvl = true
+                                trx.wait_for_cpn_lvl = true
                             }
+
+                            trx.current_tax = Some(TaxRecord {
+                                primary_ticket_no: rec.primary_ticket_no.clone(),
+                                ticket_no: rec.ticket_no.clone(),
+                                coupon_no: rec.coupon_no.clone(),
+                                nature_code,
+                                iso_code,
+                                is_refundable,
+                                amount_accounting_currency: String::new(),
+                            });
                             
                          }
                     
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponTaxes", "CollectedTaxesCpnLvl", "Tax", "AccountableEntity", "Amount", "AmountType"] 
-                        if wait_for_cpn_lvl => {
-                            let txt = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_TAXES, COLLECTED_TAXES_CPN_LVL, TAX, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE] => {
+                            let txt = read_text(reader, &mut text_buf)?;
                             if txt == "ACCOUNTED" {
-                               wait_for_cpn_lvl_accounted = true;
+                               if trx.wait_for_cpn_lvl {
+                                   trx.wait_for_cpn_lvl_accounted = true;
+                               }
+                               trx.waiting_for_tax_amount = trx.current_tax.is_some();
                             }
                             
                          }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "StandardCommission", "Commission", "AccountableEntity", "Amount", "AmountType"] => {
-                        let txt = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, STANDARD_COMMISSION, COMMISSION, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
                         if txt == "ACCOUNTED" {
-                            waiting_for_std_comm_amount = true;
+                            trx.waiting_for_std_comm_amount = true;
                         }
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "SupplementaryCommission", "Commission", "AccountableEntity", "Amount", "AmountType"] => {
-                        let txt = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, SUPPLEMENTARY_COMMISSION, COMMISSION, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT_TYPE] => {
+                        let txt = read_text(reader, &mut text_buf)?;
                         if txt == "ACCOUNTED" {
-                            waiting_for_supp_comm_amount = true;
+                            trx.waiting_for_supp_comm_amount = true;
                         }
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "SegmentInfo"] => {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO] => {
                         let origin = get_attr_val(&e, b"OriginAirportCode");
                         let dest = get_attr_val(&e, b"DestinationAirportCode");
                         rec.segment = format!("{}{}", origin, dest);
@@ -173,16 +411,16 @@ pub fn parse_xml<R: BufRead>(reader: &mut Reader<R>) -> Result<Vec<Record>> {
                         rec.arr_date_time = get_attr_val(&e, b"ArrivalDate");
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "SegmentInfo", "ClassDetails", "BookingClass"] => {
-                        rec.rbd = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, CLASS_DETAILS, BOOKING_CLASS] => {
+                        rec.rbd = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "SegmentInfo", "ClassDetails", "OperatingCabinClass"] => {
-                        rec.cabin = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, CLASS_DETAILS, OPERATING_CABIN_CLASS] => {
+                        rec.cabin = read_text(reader, &mut text_buf)?;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "SegmentInfo", "FlightIdentification", "OperatingFlightNumber", "FlightNumber"] => {
-                        rec.flight_nr = read_text(reader)?;
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, SEGMENT_INFO, FLIGHT_IDENTIFICATION, OPERATING_FLIGHT_NUMBER, FLIGHT_NUMBER] => {
+                        rec.flight_nr = read_text(reader, &mut text_buf)?;
                     }
 
                     _ => {}
@@ -190,124 +428,197 @@ pub fn parse_xml<R: BufRead>(reader: &mut Reader<R>) -> Result<Vec<Record>> {
             }
 
             Event::Empty(e) => {
-                let tag = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
-                path.push(tag.clone());
+                path.open(&e)?;
 
-                let path_ref: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+                mandatory.observe(path);
+                observe_control(&mut stats.control, path, &e, options);
+                let path_ref = path.segments();
 
-                match path_ref.as_slice() {
-                    ["AMA_REV.Feed", "Transaction", "Document", "IssuanceDetails"] => {
+                match path_ref {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, ISSUANCE_DETAILS] => {
                         rec.pos = get_attr_val(&e, b"CityPOS");
                         rec.iata = get_attr_val(&e, b"Iata");
                         rec.distribution_channel = get_attr_val(&e, b"OfficeId");
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Fares", "Fare", "AccountableEntity", "Amount", "Amount"]
-                        if in_pricing_fares && waiting_for_amount_fare =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, FARES, FARE, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT]
+                        if trx.in_pricing_fares && trx.waiting_for_amount_fare =>
                     {
                         let amt = get_attr_val(&e, b"Amount");
-                        if last_fare_type == "NET" {
-                            rec.net_fare_amount_accounting_currency = amt;
-                        } else if last_fare_type == "PUBLISHED" {
-                            rec.pub_fare_amount_accounting_currency = amt;
-                        } else if last_fare_type == "ADDITIONAL_COLLECTION" {
-                            rec.bal_exchange_additional_collected_fare_amount_accounting_currency = amt;
+                        if let Some(column) = trx.last_fare_column {
+                            *column.field_mut(&mut rec) = amt;
                         }
-                        waiting_for_amount_fare = false;
+                        trx.waiting_for_amount_fare = false;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponProratedFare", "AccountableEntity", "Amount", "Amount"]
-                        if in_calculated_amounts && waiting_for_amount_proratedfare =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_PRORATED_FARE, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT]
+                        if trx.in_calculated_amounts && trx.waiting_for_amount_proratedfare =>
                     {
                         let temp_val = get_attr_val(&e, b"Amount");
-                        temp_cpn_amount = temp_val.parse::<f64>().unwrap_or(0.0);
+                        trx.temp_cpn_amount = temp_val.parse::<f64>().unwrap_or(0.0);
+                        stats.control.accounted_fare_amount += trx.temp_cpn_amount;
+                        trx.last_accounted_fare = trx.temp_cpn_amount;
                         rec.cpn_far_fare_amount_accounting_currency = temp_val;
-                        waiting_for_amount_proratedfare = false;
+                        let currency = get_attr_val(&e, b"Currency");
+                        if !currency.is_empty() {
+                            rec.accounting_currency = currency;
+                        }
+                        trx.waiting_for_amount_proratedfare = false;
                     }
 
                     // ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponTaxes", "CollectedTaxesCpnLvl", "Tax", "AccountableEntity", "Amount", "Amount"] 
-                    //     if in_calculated_amounts && wait_for_cpn_lvl_accounted && wait_for_cpn_lvl => {
+                    //     if trx.in_calculated_amounts && trx.wait_for_cpn_lvl_accounted && trx.wait_for_cpn_lvl => {
                     //         let temp_cpnlvl = get_attr_val(&e, b"Amount");
-                    //         temp_tax_amount = temp_cpnlvl.parse::<f64>().unwrap_or(0.0);
+                    //         trx.temp_tax_amount = temp_cpnlvl.parse::<f64>().unwrap_or(0.0);
                     //         rec.cpn_txo_tax_amount_accounting_currency_yq = temp_cpnlvl;
                             
                     //      }
                     
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponTaxes", "CollectedTaxesCpnLvl", "Tax", "AccountableEntity", "Amount", "Amount"] 
-                        if in_calculated_amounts && wait_for_cpn_lvl_accounted => {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_TAXES, COLLECTED_TAXES_CPN_LVL, TAX, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT] 
+                        if trx.in_calculated_amounts => {
                             let temp_cpnlvl_tax_sum = get_attr_val(&e, b"Amount"); // String
 
-                            let amount: f64 = temp_cpnlvl_tax_sum
-                                .parse::<f64>()
-                                .unwrap_or(0.0);
+                            if trx.waiting_for_tax_amount {
+                                if let Some(mut tax) = trx.current_tax.take() {
+                                    tax.amount_accounting_currency = temp_cpnlvl_tax_sum.clone();
+                                    taxes.push(tax);
+                                }
+                                trx.waiting_for_tax_amount = false;
+                            }
+
+                            if trx.wait_for_cpn_lvl_accounted {
+                                let amount: f64 = temp_cpnlvl_tax_sum
+                                    .parse::<f64>()
+                                    .unwrap_or(0.0);
 
-                            total_cpn_amount += amount;
+                                trx.total_cpn_amount += amount;
 
-                            if wait_for_cpn_lvl {
-                                temp_tax_amount = amount;
-                                rec.cpn_txo_tax_amount_accounting_currency_yq = temp_cpnlvl_tax_sum;
+                                if trx.wait_for_cpn_lvl {
+                                    trx.temp_tax_amount = amount;
+                                    rec.cpn_txo_tax_amount_accounting_currency_yq = temp_cpnlvl_tax_sum;
 
+                                }
                             }
                          }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "Coupon", "CalculatedAmounts", "CouponStandardCommission", "Commission", "AccountableEntity", "Amount", "Amount"]
-                        if in_coup_standard_comm_amounts_1 && in_coup_standard_comm_amounts_2 && waiting_for_coup_standard_comm_amount =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, CALCULATED_AMOUNTS, COUPON_STANDARD_COMMISSION, COMMISSION, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT]
+                        if trx.in_coup_standard_comm_amounts_1 && trx.in_coup_standard_comm_amounts_2 && trx.waiting_for_coup_standard_comm_amount =>
                     {
                         rec.cpn_std_commission_amount_accounting_currency = get_attr_val(&e, b"Amount");
-                        waiting_for_coup_standard_comm_amount = false;
+                        trx.waiting_for_coup_standard_comm_amount = false;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "StandardCommission", "Commission", "AccountableEntity", "Amount", "Amount"]
-                        if waiting_for_std_comm_amount =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, STANDARD_COMMISSION, COMMISSION, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT]
+                        if trx.waiting_for_std_comm_amount =>
                     {
                         rec.std_commission_amount_accounting_currency = get_attr_val(&e, b"Amount");
-                        waiting_for_std_comm_amount = false;
+                        trx.waiting_for_std_comm_amount = false;
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "SupplementaryCommission", "Commission", "AccountableEntity", "Amount", "Amount"]
-                        if waiting_for_supp_comm_amount =>
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, SUPPLEMENTARY_COMMISSION, COMMISSION, ACCOUNTABLE_ENTITY, AMOUNT, AMOUNT]
+                        if trx.waiting_for_supp_comm_amount =>
                     {
                         rec.sup_commision_amount_accounting_currency = get_attr_val(&e, b"Amount");
-                        waiting_for_supp_comm_amount = false;
+                        trx.waiting_for_supp_comm_amount = false;
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, REFERENCED_DOCUMENTS, REFERENCED_DOCUMENT] => {
+                        links.push(document_link(&e, &rec));
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, REFERENCED_DOCUMENTS, REFERENCED_DOCUMENT, REFERENCED_COUPON] => {
+                        if let Some(base) = &trx.current_link {
+                            let mut link = base.clone();
+                            link.linked_coupon_no = get_attr_val(&e, b"Number");
+                            links.push(link);
+                            trx.current_link_coupons += 1;
+                        }
                     }
 
-                    ["AMA_REV.Feed", "Transaction", "Document", "PricingDetails", "RevenueAttributableAgent"] => {
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON, REFERENCED_COUPON] => {
+                        let mut link = document_link(&e, &rec);
+                        link.coupon_no = rec.coupon_no.clone();
+                        link.linked_coupon_no = get_attr_val(&e, b"Number");
+                        links.push(link);
+                    }
+
+                    [AMA_REV_FEED, TRANSACTION, DOCUMENT, PRICING_DETAILS, REVENUE_ATTRIBUTABLE_AGENT] => {
                         rec.trx_revenue_attributable_iata_number = get_attr_val(&e, b"AgencyNumber");
                     }
 
                     _ => {}
                 }
 
-                path.pop();
+                path.close();
             }
 
             Event::End(e) => {
                 if e.local_name().as_ref() == b"Fares" {
-                    in_pricing_fares = false;
+                    trx.in_pricing_fares = false;
                 }
                 if e.local_name().as_ref() == b"CalculatedAmounts" {
-                    in_calculated_amounts = false;
-                    let temp_revenue = temp_cpn_amount + temp_tax_amount;
-                    rec.sum_cpn_txo_tax_amount_accounting_currency = total_cpn_amount.to_string();
+                    trx.in_calculated_amounts = false;
+                    let temp_revenue = trx.temp_cpn_amount + trx.temp_tax_amount;
+                    rec.sum_cpn_txo_tax_amount_accounting_currency = trx.total_cpn_amount.to_string();
                     rec.revenue = temp_revenue.to_string();
-                    temp_cpn_amount = 0.0;
-                    temp_tax_amount = 0.0;
-                    wait_for_cpn_lvl_accounted = false;
-                    wait_for_cpn_lvl = false;
-                    waiting_for_amount_proratedfare = false;
+                    trx.temp_cpn_amount = 0.0;
+                    trx.temp_tax_amount = 0.0;
+                    trx.wait_for_cpn_lvl_accounted = false;
+                    trx.wait_for_cpn_lvl = false;
+                    trx.waiting_for_amount_proratedfare = false;
+                }
+                if e.local_name().as_ref() == b"Tax" {
+                    // taxes without an accounted amount are not emitted
+                    trx.current_tax = None;
+                    trx.waiting_for_tax_amount = false;
+                }
+                if e.local_name().as_ref() == b"ReferencedDocument" {
+                    // a document reference without coupon references is kept on its own
+                    if let Some(link) = trx.current_link.take()
+                        && trx.current_link_coupons == 0
+                    {
+                        links.push(link);
+                    }
                 }
                 if e.local_name().as_ref() == b"CouponStandardCommission" {
-                    in_coup_standard_comm_amounts_1 = false;
-                    in_coup_standard_comm_amounts_2 = false;
+                    trx.in_coup_standard_comm_amounts_1 = false;
+                    trx.in_coup_standard_comm_amounts_2 = false;
                 }
 
                 if e.local_name().as_ref() == b"Transaction" {
+                    // document references can precede the coupons carrying the ticket number
+                    for link in &mut links[trx_link_start..] {
+                        if link.primary_ticket_no.is_empty() {
+                            link.primary_ticket_no = rec.primary_ticket_no.clone();
+                        }
+                        link.document_status = rec.document_status.clone();
+                    }
+                    if let Some(first) = links.get(trx_link_start) {
+                        rec.linked_ticket_no = first.linked_ticket_no.clone();
+                        rec.link_type = first.link_type.clone();
+                    }
+
                     // push record for completed transaction and reset
-                    rows.push(rec);
+                    match options.rejection(&trx.event_type, &rec.document_status, &rec.coupon_status) {
+                        None => {
+                            stats.control.records += 1;
+                            stats.control.kept_accounted_fare_amount += trx.last_accounted_fare;
+                            transactions.push(FeedRowCounts {
+                                taxes: taxes.len() - trx_tax_start,
+                                links: links.len() - trx_link_start,
+                            });
+                            on_record(rec)?;
+                        }
+                        Some(reason) => {
+                            *stats.filtered.entry(reason).or_default() += 1;
+                            taxes.truncate(trx_tax_start);
+                            links.truncate(trx_link_start);
+                        }
+                    }
                     rec = Record::default();
                 }
 
-                path.pop();
+                path.close();
             }
 
             Event::Eof => break,
@@ -317,21 +628,51 @@ pub fn parse_xml<R: BufRead>(reader: &mut Reader<R>) -> Result<Vec<Record>> {
         buf.clear();
     }
 
-    Ok(rows)
+    Ok(ParsedFeed { records: Vec::new(), taxes, links, transactions, stats })
 }
 
+// Counts the elements reconciliation checks and reads the control totals the feed declares
+fn observe_control(control: &mut ControlCounts, path: &FeedPath, e: &BytesStart, options: &ParseOptions) {
+    match path.segments() {
+        [AMA_REV_FEED, TRANSACTION] => control.transactions += 1,
+        [AMA_REV_FEED, TRANSACTION, DOCUMENT] => control.documents += 1,
+        [AMA_REV_FEED, TRANSACTION, DOCUMENT, COUPON] => control.coupons += 1,
+        _ => {}
+    }
 
-// To read the text betweent the tags 
-fn read_text<R: BufRead>(reader: &mut Reader<R>) -> Result<String> {
-    let mut buf = Vec::new();
-    if let Event::Text(e) = reader.read_event_into(&mut buf)? {
+    for total in &options.control_totals {
+        if total.matches(path.below_root())
+            && let Ok(value) = get_attr_val(e, total.attribute.as_bytes()).trim().parse()
+        {
+            control.declared.insert(total.metric.to_string(), value);
+        }
+    }
+}
+
+// Reference to another document from a ReferencedDocument/ReferencedCoupon element
+fn document_link(e: &BytesStart, rec: &Record) -> DocumentLinkRecord {
+    DocumentLinkRecord {
+        primary_ticket_no: rec.primary_ticket_no.clone(),
+        ticket_no: rec.ticket_no.clone(),
+        link_type: get_attr_val(e, b"ReferenceType"),
+        linked_ticket_no: get_attr_val(e, b"DocumentNbr"),
+        linked_issue_date: get_attr_val(e, b"DateOfIssuance"),
+        ..Default::default()
+    }
+}
+
+
+/// To read the text betweent the tags, `buf` is reused across calls
+pub fn read_text<R: BufRead>(reader: &mut Reader<R>, buf: &mut Vec<u8>) -> Result<String> {
+    buf.clear();
+    if let Event::Text(e) = reader.read_event_into(buf)? {
         return Ok(e.unescape()?.to_string());
     }
     Ok(String::new())
 }
 
-// To read the attributes within the tags 
-fn get_attr_val(e: &BytesStart, key: &[u8]) -> String {
+/// To read the attributes within the tags 
+pub fn get_attr_val(e: &BytesStart, key: &[u8]) -> String {
     for a in e.attributes().flatten() {
         if a.key.local_name().as_ref() == key {
             return a.unescape_value().unwrap_or_default().to_string();
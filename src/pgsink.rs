@@ -0,0 +1,211 @@
+use crate::models::Record;
+use crate::sink::RecordSink;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use csv::WriterBuilder;
+use futures_util::SinkExt;
+use tokio_postgres::{Client, NoTls};
+
+// Natural key used when upserting from the staging table into the target table
+pub const NATURAL_KEY: [&str; 3] = ["ticket_no", "coupon_no", "document_status"];
+// Of several staging rows with one natural key, the latest by this column is upserted
+const LATEST_BY: &str = "transaction_timestamp";
+
+pub struct PgSink {
+    client: Client,
+    staging_table: String,
+    target_table: Option<String>,
+    columns: Vec<String>,
+    batch: Vec<Record>,
+    batch_size: usize,
+    in_transaction: bool,
+    // rows of the current file left out of the upsert for lack of a ticket number
+    without_ticket_no: usize,
+}
+
+impl PgSink {
+    pub async fn connect(
+        conn_str: &str,
+        staging_table: &str,
+        target_table: Option<&str>,
+        batch_size: usize,
+    ) -> Result<Self> {
+        let (client, connection) = tokio_postgres::connect(conn_str, NoTls)
+            .await
+            .context("connecting to PostgreSQL")?;
+
+        // the connection object drives the socket, run it in the background
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("PostgreSQL connection error: {}", e);
+            }
+        });
+
+        let sink = Self {
+            client,
+            staging_table: staging_table.to_string(),
+            target_table: target_table.map(|t| t.to_string()),
+            columns: record_columns()?,
+            batch: Vec::with_capacity(batch_size),
+            batch_size: batch_size.max(1),
+            in_transaction: false,
+            without_ticket_no: 0,
+        };
+
+        // without a target table the staging table is where the rows stay; it holds
+        // the rows of the current run, not every run before it
+        if sink.target_table.is_none() {
+            sink.clear_staging().await?;
+        }
+        Ok(sink)
+    }
+
+    async fn clear_staging(&self) -> Result<()> {
+        self.client
+            .batch_execute(&format!("DELETE FROM {}", quote_ident(&self.staging_table)))
+            .await
+            .context("clearing the staging table")
+    }
+
+    async fn flush_batch(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        // rows are sent as CSV in the same column order as the COPY statement
+        let mut writer = WriterBuilder::new().has_headers(false).from_writer(Vec::new());
+        for rec in &self.batch {
+            writer.serialize(rec)?;
+        }
+        let data = writer.into_inner()?;
+
+        let stmt = format!(
+            "COPY {} ({}) FROM STDIN WITH (FORMAT csv)",
+            quote_ident(&self.staging_table),
+            self.column_list(),
+        );
+        let sink = self.client.copy_in::<_, Bytes>(&stmt).await?;
+        let mut sink = std::pin::pin!(sink);
+        sink.send(Bytes::from(data)).await?;
+        sink.as_mut().finish().await?;
+
+        self.batch.clear();
+        Ok(())
+    }
+
+    fn column_list(&self) -> String {
+        self.columns
+            .iter()
+            .map(|c| quote_ident(c))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn upsert_statement(&self, target: &str) -> String {
+        let key = NATURAL_KEY.map(quote_ident).join(", ");
+        let updates = self
+            .columns
+            .iter()
+            .filter(|c| !NATURAL_KEY.contains(&c.as_str()))
+            .map(|c| format!("{0} = EXCLUDED.{0}", quote_ident(c)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        // DISTINCT ON keeps a single staging row per key, the latest one, as deduplication
+        // does; ON CONFLICT cannot touch a row twice. Timestamps in one output format
+        // order as text.
+        format!(
+            "INSERT INTO {target} ({cols}) SELECT DISTINCT ON ({key}) {cols} FROM {staging} \
+             ORDER BY {key}, {latest} DESC \
+             ON CONFLICT ({key}) DO UPDATE SET {updates}",
+            target = quote_ident(target),
+            cols = self.column_list(),
+            key = key,
+            staging = quote_ident(&self.staging_table),
+            latest = quote_ident(LATEST_BY),
+            updates = updates,
+        )
+    }
+}
+
+impl RecordSink<Record> for PgSink {
+    // Opens the transaction that covers every row of one source file. With a target
+    // table, staging starts out empty so only this file's rows get upserted.
+    async fn begin_file(&mut self) -> Result<()> {
+        if self.in_transaction {
+            // a file that failed without abort_file
+            self.abort_file().await?;
+        }
+        self.client.batch_execute("BEGIN").await?;
+        self.in_transaction = true;
+        if self.target_table.is_some() {
+            self.clear_staging().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_record(&mut self, rec: &Record) -> Result<()> {
+        // without a ticket number (no ConjunctiveDocumentNbr) rows would all share one key
+        if self.target_table.is_some() && rec.ticket_no.is_empty() {
+            self.without_ticket_no += 1;
+            return Ok(());
+        }
+        self.batch.push(rec.clone());
+        if self.batch.len() >= self.batch_size {
+            self.flush_batch().await?;
+        }
+        Ok(())
+    }
+
+    // Flushes the last batch, upserts into the target table if configured and commits
+    async fn end_file(&mut self) -> Result<()> {
+        self.flush_batch().await?;
+
+        if let Some(target) = &self.target_table {
+            if self.without_ticket_no > 0 {
+                println!("Left {} records without ticket_no out of {}", self.without_ticket_no, target);
+                self.without_ticket_no = 0;
+            }
+            let upsert = self.upsert_statement(target);
+            self.client.batch_execute(&upsert).await.context("upserting into the target table")?;
+            self.clear_staging().await?;
+        }
+
+        self.client.batch_execute("COMMIT").await?;
+        self.in_transaction = false;
+        Ok(())
+    }
+
+    // Rolls back the file's transaction, which also takes its rows out of staging, and
+    // leaves the connection ready for the next file
+    async fn abort_file(&mut self) -> Result<()> {
+        self.batch.clear();
+        self.without_ticket_no = 0;
+        if self.in_transaction {
+            self.in_transaction = false;
+            self.client.batch_execute("ROLLBACK").await?;
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        if self.in_transaction {
+            self.end_file().await?;
+        }
+        Ok(())
+    }
+}
+
+// Column names of Record, taken from the CSV header serde produces for it
+fn record_columns() -> Result<Vec<String>> {
+    let mut writer = WriterBuilder::new().from_writer(Vec::new());
+    writer.serialize(Record::default())?;
+    let data = writer.into_inner()?;
+    let text = String::from_utf8(data)?;
+    let header = text.lines().next().unwrap_or_default();
+    Ok(header.split(',').map(|s| s.to_string()).collect())
+}
+
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
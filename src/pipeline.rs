@@ -0,0 +1,1070 @@
+use anyhow::{Result, bail};
+use chrono::Local;
+use quick_xml::Reader;
+use std::io::Cursor;
+use std::time::Instant;
+
+use crate::config;
+use crate::csvchunker::CsvChunkerWriter;
+use crate::datetimes::DateNormalizer;
+use crate::dedupe::{DedupePolicy, DedupeStage, DedupeState};
+use crate::fx::{FxConverter, FxRateSource};
+use crate::manifest::RunManifest;
+use crate::models::{DocumentLinkRecord, NormalizedFeed, ParseStats, Record, TaxRecord, TextColumns, TransactionRows};
+use crate::normalized::SurrogateKeys;
+#[cfg(feature = "pg")]
+use crate::pgsink::PgSink;
+#[cfg(feature = "protect")]
+use crate::protect::FieldProtector;
+use crate::reconcile::OutputCounts;
+use crate::refdata::{HaulThresholds, ReferenceData};
+use crate::sink::RecordSink;
+#[cfg(feature = "sql")]
+use crate::sqltransform::SqlTransform;
+use crate::storage::{ChecksumStorage, ObjectInfo, Storage};
+use crate::summary::DailySummary;
+#[cfg(feature = "validate")]
+use crate::validate::XsdValidator;
+
+// Stand-ins for the sink and validator of disabled features. They have no values, so
+// the code paths that use them are never taken; see make_pg_sink and make_validator.
+#[cfg(not(feature = "pg"))]
+enum PgSink {}
+
+#[cfg(not(feature = "pg"))]
+impl RecordSink<Record> for PgSink {
+    async fn write_record(&mut self, _rec: &Record) -> Result<()> {
+        match *self {}
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "validate"))]
+enum XsdValidator {}
+
+/// Processes every XML object under INPUT_PREFIX once
+pub async fn run_once<S: Storage + Clone>(storage: &S) -> Result<()> {
+
+    let start_time = Instant::now();
+    let timestamp = Local::now().format(config::TIME_FORMAT).to_string();
+    let input_prefix: &str = config::INPUT_PREFIX;
+    let input_bucket: &str = config::INPUT_BUCKET;
+
+    // list keys (propagate errors)
+    let list_of_objects = list_xml_objects(storage, input_bucket, input_prefix).await?;
+
+    // create csv chunkers (each one owns a clone of the storage handle, which checksums
+    // every upload for the manifest)
+    let outputs = ChecksumStorage::new(storage.clone(), config::UPLOAD_CHECKSUM);
+    let mut writers = ChunkWriters::new(&outputs, "", timestamp.as_str()).await?;
+
+    let mut pg_sink = make_pg_sink().await?;
+    let enrichment = Enrichment::load(storage).await?;
+    let validator = make_validator(storage).await?;
+    let mut stats = ParseStats::default();
+    let mut manifest = RunManifest::new(&timestamp);
+    manifest.protection = enrichment.describe_protection();
+
+    // with dedupe on, records are held back until every file was read
+    let mut dedupe = match load_dedupe_state(storage).await? {
+        Some(state) => Some(make_dedupe_stage(state)?),
+        None => None,
+    };
+
+    for object in list_of_objects {
+        let Some(bytes) = fetch_input(storage, input_bucket, &object, validator.as_ref()).await? else {
+            continue;
+        };
+        manifest.inputs.push(object.key.clone());
+        let file_stats = process_key(
+            &bytes,
+            &object.key,
+            &enrichment,
+            &mut writers,
+            pg_sink.as_mut(),
+            dedupe.as_mut(),
+        )
+        .await?;
+        stats.merge(&file_stats);
+    }
+
+    if let Some(stage) = dedupe {
+        // deduplicated rows may come from any input
+        writers.set_source(&crate::storage::source_keys_metadata(&manifest.inputs));
+        let mut state = write_deduped(stage, &enrichment, &mut writers, pg_sink.as_mut(), stats.control.records).await?;
+        save_dedupe_state(storage, &mut state).await?;
+    }
+
+    writers.finalize().await?;
+    if let Some(pg) = pg_sink.as_mut() {
+        pg.finalize().await?;
+    }
+    if config::MANIFEST_ENABLED {
+        manifest.outputs = outputs.uploads();
+        manifest.write(storage, config::OUTPUT_BUCKET, config::MANIFEST_PREFIX).await?;
+    }
+    print_stats(&stats);
+    enrichment.print_summary();
+    let duration = start_time.elapsed();
+    println!("Processing completed in: {:?}", duration);
+
+
+    Ok(())
+}
+
+// Writes the records a dedupe stage kept to every sink and returns the key state to
+// save once they are written. `records` is the number pushed, for the row count check.
+async fn write_deduped<S: Storage + Clone>(
+    stage: DedupeStage,
+    enrichment: &Enrichment,
+    writers: &mut ChunkWriters<S>,
+    mut pg_sink: Option<&mut PgSink>,
+    records: u64,
+) -> Result<DedupeState> {
+    let mut deduped = stage.finish()?;
+    let rows_before = writers.records.rows_written();
+    if let Some(pg) = pg_sink.as_mut() {
+        pg.begin_file().await?;
+    }
+    let written = async {
+        // the SQL transform sees every deduplicated record at once
+        let mut held = Vec::new();
+        while let Some((rec, rows)) = deduped.next_transaction()? {
+            if let Some(pg) = pg_sink.as_mut() {
+                pg.write_record(&rec).await?;
+            }
+            if let Some(summary) = writers.summary.as_mut() {
+                summary.write_record(&rec).await?;
+            }
+            if enrichment.has_transform() {
+                held.push(rec);
+            } else {
+                writers.records.write_record(&rec).await?;
+            }
+            writers.write_side_rows(&rows.taxes, &rows.links, Some(&rows.normalized)).await?;
+        }
+        enrichment.write_records(&mut writers.records, held).await?;
+        if let Some(pg) = pg_sink.as_mut() {
+            pg.end_file().await?;
+        }
+        Ok(())
+    }
+    .await;
+    abort_on_error(pg_sink, written).await?;
+    println!("Removed {} duplicate records", deduped.removed());
+
+    // per-file checks could not see the rows, they are only written now
+    if !enrichment.has_transform() {
+        let expected = records - deduped.removed() as u64;
+        let rows = writers.records.rows_written() - rows_before;
+        if rows != expected {
+            let mismatch = format!("{} records after deduplication but {} rows written", expected, rows);
+            crate::reconcile::report("run", &[mismatch], config::RECONCILIATION_POLICY)?;
+        }
+    }
+
+    Ok(deduped.into_state())
+}
+
+/// Long-running mode: processes each XML object announced by an S3 ObjectCreated
+/// notification on the SQS queue. Messages are deleted only once all their objects
+/// went through; on failure they become visible again after the visibility timeout.
+/// Errors talking to SQS are logged and retried with backoff.
+#[cfg(feature = "aws")]
+pub async fn run_worker<S: Storage + Clone>(storage: &S, sqs_client: &aws_sdk_sqs::Client) -> Result<()> {
+    let mut worker = Worker::new(storage, sqs_client, config::SQS_QUEUE_URL).await?;
+
+    println!("Waiting for messages on {}", config::SQS_QUEUE_URL);
+
+    let mut backoff = std::time::Duration::ZERO;
+    loop {
+        match worker.poll().await {
+            Ok(_) => backoff = std::time::Duration::ZERO,
+            Err(e) => {
+                let max = std::time::Duration::from_secs(config::SQS_RETRY_MAX_SECONDS);
+                backoff = (backoff * 2).clamp(std::time::Duration::from_secs(1), max);
+                let context = format!("polling {} failed, retrying in {:?}", config::SQS_QUEUE_URL, backoff);
+                crate::diagnostics::log_error(&context, &e);
+                tokio::time::sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// Consumer of one SQS queue in worker mode. Each `poll` handles one batch of
+/// messages, which lets tests drive it against a local stand-in such as ElasticMQ.
+#[cfg(feature = "aws")]
+pub struct Worker<'a, S: Storage + Clone> {
+    storage: &'a S,
+    sqs_client: &'a aws_sdk_sqs::Client,
+    queue_url: String,
+    wait_time_seconds: i32,
+    pg_sink: Option<PgSink>,
+    enrichment: Enrichment,
+    validator: Option<XsdValidator>,
+    // loaded before a batch, dropped after a failed message so the saved one is reloaded
+    dedupe_state: Option<DedupeState>,
+}
+
+#[cfg(feature = "aws")]
+impl<'a, S: Storage + Clone> Worker<'a, S> {
+    pub async fn new(storage: &'a S, sqs_client: &'a aws_sdk_sqs::Client, queue_url: &str) -> Result<Self> {
+        Ok(Self {
+            storage,
+            sqs_client,
+            queue_url: queue_url.to_string(),
+            wait_time_seconds: config::SQS_WAIT_TIME_SECONDS,
+            pg_sink: make_pg_sink().await?,
+            enrichment: Enrichment::load(storage).await?,
+            validator: make_validator(storage).await?,
+            dedupe_state: None,
+        })
+    }
+
+    /// Long-poll time of a receive, SQS_WAIT_TIME_SECONDS by default
+    pub fn with_wait_time(mut self, seconds: i32) -> Self {
+        self.wait_time_seconds = seconds;
+        self
+    }
+
+    /// Receives one batch of messages and processes them. Returns the number of
+    /// messages done and deleted; a message that fails is logged and left on the queue.
+    pub async fn poll(&mut self) -> Result<usize> {
+        if self.dedupe_state.is_none() {
+            self.dedupe_state = load_dedupe_state(self.storage).await?;
+        }
+
+        let messages = crate::sqs::receive_messages(
+            self.sqs_client,
+            &self.queue_url,
+            config::SQS_MAX_MESSAGES,
+            self.wait_time_seconds,
+        )
+        .await?;
+
+        let mut done = 0;
+        for message in messages {
+            let body = message.body().unwrap_or_default();
+            let processed = process_message(
+                self.storage,
+                body,
+                &self.enrichment,
+                self.validator.as_ref(),
+                self.pg_sink.as_mut(),
+                self.dedupe_state.as_mut(),
+            )
+            .await;
+
+            match processed {
+                Ok(()) => {
+                    self.enrichment.print_summary();
+                    if let Some(handle) = message.receipt_handle() {
+                        crate::sqs::delete_message(self.sqs_client, &self.queue_url, handle).await?;
+                    }
+                    done += 1;
+                }
+                Err(e) => {
+                    let context = format!("failed to process message {}", message.message_id().unwrap_or_default());
+                    crate::diagnostics::log_error(&context, &e);
+                    // it may hold keys whose records were never written
+                    self.dedupe_state = None;
+                }
+            }
+        }
+        Ok(done)
+    }
+}
+
+// Objects of a message that were done before are skipped, so a message that failed
+// part way and is delivered again does not write the earlier objects twice
+#[cfg(feature = "aws")]
+async fn process_message<S: Storage + Clone>(
+    storage: &S,
+    body: &str,
+    enrichment: &Enrichment,
+    validator: Option<&XsdValidator>,
+    mut pg_sink: Option<&mut PgSink>,
+    mut dedupe_state: Option<&mut DedupeState>,
+) -> Result<()> {
+    for (bucket, object) in crate::sqs::created_objects(body)? {
+        let key = &object.key;
+        if !is_input_key(key) {
+            continue;
+        }
+        if is_done(storage, &bucket, &object).await? {
+            println!("Skipping {:?}, it was processed before", key);
+            continue;
+        }
+        let Some(bytes) = fetch_input(storage, &bucket, &object, validator).await? else {
+            mark_done(storage, &bucket, &object).await?;
+            continue;
+        };
+
+        // one chunk series per object so that concurrent files never share an S3 key
+        let timestamp = Local::now().format(config::TIME_FORMAT).to_string();
+        let suffix = format!("_{}", object_stem(key));
+        let outputs = ChecksumStorage::new(storage.clone(), config::UPLOAD_CHECKSUM);
+        let mut writers = ChunkWriters::new(&outputs, &suffix, timestamp.as_str()).await?;
+        let mut manifest = RunManifest::new(&timestamp);
+        manifest.protection = enrichment.describe_protection();
+        manifest.inputs.push(format!("s3://{}/{}", bucket, key));
+
+        // duplicates are dropped within the object and against the keys written before
+        let stats = match dedupe_state.as_deref_mut() {
+            Some(state) => {
+                let mut stage = make_dedupe_stage(std::mem::take(state))?;
+                let stats = process_key(&bytes, key, enrichment, &mut writers, None, Some(&mut stage)).await?;
+                *state = write_deduped(stage, enrichment, &mut writers, pg_sink.as_deref_mut(), stats.control.records).await?;
+                stats
+            }
+            None => process_key(&bytes, key, enrichment, &mut writers, pg_sink.as_deref_mut(), None).await?,
+        };
+        writers.finalize().await?;
+        if let Some(state) = dedupe_state.as_deref_mut() {
+            save_dedupe_state(storage, state).await?;
+        }
+        if config::MANIFEST_ENABLED {
+            let prefix = format!("{}{}", config::MANIFEST_PREFIX, suffix);
+            manifest.outputs = outputs.uploads();
+            manifest.write(storage, config::OUTPUT_BUCKET, &prefix).await?;
+        }
+        mark_done(storage, &bucket, &object).await?;
+        print_stats(&stats);
+    }
+
+    Ok(())
+}
+
+// Completion marker of an object in OUTPUT_BUCKET; it holds the object's ETag, so a
+// new version uploaded under the same key is processed again
+#[cfg(feature = "aws")]
+fn done_marker(bucket: &str, object: &ObjectInfo) -> String {
+    format!("{}{}/{}", config::WORKER_DONE_PREFIX, bucket, object.key)
+}
+
+#[cfg(feature = "aws")]
+async fn is_done<S: Storage>(storage: &S, bucket: &str, object: &ObjectInfo) -> Result<bool> {
+    let marker = done_marker(bucket, object);
+    if !storage.list(config::OUTPUT_BUCKET, &marker).await?.contains(&marker) {
+        return Ok(false);
+    }
+    let etag = storage.get(config::OUTPUT_BUCKET, &marker).await?;
+    Ok(etag == object.etag.clone().unwrap_or_default().into_bytes())
+}
+
+#[cfg(feature = "aws")]
+async fn mark_done<S: Storage>(storage: &S, bucket: &str, object: &ObjectInfo) -> Result<()> {
+    let etag = object.etag.clone().unwrap_or_default();
+    storage.put(config::OUTPUT_BUCKET, &done_marker(bucket, object), etag.into_bytes()).await
+}
+
+// Downloads an XML object and checks it against the listing; with XSD validation on,
+// a non-conforming object is quarantined and None is returned
+async fn fetch_input<S: Storage>(
+    storage: &S,
+    bucket: &str,
+    object: &ObjectInfo,
+    validator: Option<&XsdValidator>,
+) -> Result<Option<Vec<u8>>> {
+    let key = object.key.as_str();
+    println!("Processing {:?}", key);
+
+    let bytes = storage.get_verified(bucket, object).await?;
+
+    match validator {
+        Some(validator) => check_schema(storage, bucket, key, validator, bytes).await,
+        None => Ok(Some(bytes)),
+    }
+}
+
+// Quarantines a non-conforming object and returns None, passes the others through
+#[cfg(feature = "validate")]
+async fn check_schema<S: Storage>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    validator: &XsdValidator,
+    bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+    let violations = validator.validate(&bytes)?;
+    if violations.is_empty() {
+        return Ok(Some(bytes));
+    }
+    for v in &violations {
+        eprintln!("{}: {}", key, v);
+    }
+    crate::validate::quarantine(storage, bucket, key, config::QUARANTINE_PREFIX, bytes, &violations).await?;
+    println!("Quarantined {:?} with {} schema violations", key, violations.len());
+    Ok(None)
+}
+
+#[cfg(not(feature = "validate"))]
+async fn check_schema<S: Storage>(
+    _storage: &S,
+    _bucket: &str,
+    _key: &str,
+    validator: &XsdValidator,
+    _bytes: Vec<u8>,
+) -> Result<Option<Vec<u8>>> {
+    match *validator {}
+}
+
+// Parse and write a single downloaded XML object
+async fn process_key<S: Storage + Clone>(
+    bytes: &[u8],
+    key: &str,
+    enrichment: &Enrichment,
+    writers: &mut ChunkWriters<S>,
+    pg_sink: Option<&mut PgSink>,
+    dedupe: Option<&mut DedupeStage>,
+) -> Result<ParseStats> {
+    // parse XML into records and coupon-level taxes, large files optionally on all cores
+    let mut feed = if config::PARALLEL_PARSE_ENABLED {
+        crate::parallel::parse_xml_parallel(bytes, &crate::parser::ParseOptions::default(), config::PARALLEL_CHUNK_BYTES)
+    } else {
+        // build a Reader from the downloaded bytes
+        let mut xml_reader = Reader::from_reader(Cursor::new(bytes));
+        xml_reader.trim_text(true);
+        crate::parser::parse_xml(&mut xml_reader)
+    }
+    .map_err(|e| crate::diagnostics::locate(e, key, bytes))?;
+    let mut records = std::mem::take(&mut feed.records);
+    println!("Parsed {} records", records.len());
+    writers.set_source(key);
+
+    for rec in records.iter_mut() {
+        enrichment.apply(rec);
+    }
+
+    for tax in feed.taxes.iter_mut() {
+        enrichment.protect(tax);
+    }
+    for link in feed.links.iter_mut() {
+        enrichment.protect(link);
+    }
+
+    // normalized tables come from a second pass over the same bytes, with the same filters
+    let normalized = match writers.normalized.as_mut() {
+        Some(tables) => {
+            let options = crate::parser::ParseOptions::default();
+            let mut normalized = if config::PARALLEL_PARSE_ENABLED {
+                crate::parallel::parse_normalized_parallel(bytes, key, &mut tables.keys, &options, config::PARALLEL_CHUNK_BYTES)
+            } else {
+                let mut xml_reader = Reader::from_reader(Cursor::new(bytes));
+                xml_reader.trim_text(true);
+                crate::normalized::parse_normalized(&mut xml_reader, key, &mut tables.keys, &options)
+            }
+            .map_err(|e| crate::diagnostics::locate(e, key, bytes))?;
+            enrichment.protect_normalized(&mut normalized);
+            Some(normalized)
+        }
+        None => None,
+    };
+
+    let mut output = OutputCounts::default();
+
+    if let Some(stage) = dedupe {
+        // the other outputs of a transaction are kept or dropped with its record
+        let mut feed_rows = feed.take_transaction_rows().into_iter();
+        let mut normalized_rows = match normalized {
+            Some(normalized) => {
+                let transactions = normalized.into_transactions();
+                if transactions.len() != records.len() {
+                    bail!("{}: {} records but {} transactions in the normalized tables", key, records.len(), transactions.len());
+                }
+                transactions.into_iter()
+            }
+            None => Vec::new().into_iter(),
+        };
+        for rec in records {
+            let (taxes, links) = feed_rows.next().unwrap_or_default();
+            let rows = TransactionRows {
+                taxes: if writers.taxes.is_some() { taxes } else { Vec::new() },
+                links: if writers.links.is_some() { links } else { Vec::new() },
+                normalized: normalized_rows.next().unwrap_or_default(),
+            };
+            stage.push_transaction(rec, rows)?;
+        }
+    } else {
+        if let Some(pg) = pg_sink {
+            pg.begin_file().await?;
+            let loaded = async {
+                for rec in &records {
+                    pg.write_record(rec).await?;
+                }
+                pg.end_file().await
+            }
+            .await;
+            abort_on_error(Some(pg), loaded).await?;
+        }
+
+        if let Some(summary) = writers.summary.as_mut() {
+            for rec in &records {
+                summary.write_record(rec).await?;
+            }
+        }
+
+        // write entries into CSV chunker
+        let rows_before = writers.records.rows_written();
+        let transformed = enrichment.has_transform();
+        let fare_amount = records.iter().map(|r| r.cpn_far_fare_amount_accounting_currency.parse::<f64>().unwrap_or(0.0)).sum();
+        enrichment.write_records(&mut writers.records, records).await?;
+        if !transformed {
+            output.rows_written = Some(writers.records.rows_written() - rows_before);
+            output.fare_amount = Some(fare_amount);
+        }
+
+        writers.write_side_rows(&feed.taxes, &feed.links, normalized.as_ref()).await?;
+    }
+
+    let filtered = feed.stats.filtered.values().sum::<usize>() as u64;
+    let mismatches = crate::reconcile::reconcile(&feed.stats.control, filtered, &output);
+    crate::reconcile::report(key, &mismatches, config::RECONCILIATION_POLICY)?;
+
+    Ok(feed.stats)
+}
+
+/// Checks files against the XSD without processing them. Arguments are local paths
+/// or s3:// locations; without arguments every XML object under INPUT_PREFIX is checked.
+#[cfg(feature = "validate")]
+pub async fn run_validate<S: Storage>(storage: &S, locations: Vec<String>) -> Result<()> {
+    let validator = XsdValidator::load(storage, config::XSD_LOCATION).await?;
+
+    let locations = if locations.is_empty() {
+        list_xml_objects(storage, config::INPUT_BUCKET, config::INPUT_PREFIX)
+            .await?
+            .into_iter()
+            .map(|object| format!("s3://{}/{}", config::INPUT_BUCKET, object.key))
+            .collect()
+    } else {
+        locations
+    };
+
+    let mut invalid = 0;
+    for location in &locations {
+        let data = crate::storage::read_location(storage, location).await?;
+        let violations = validator.validate(&data)?;
+        for v in &violations {
+            println!("{}: {}", location, v);
+        }
+        if !violations.is_empty() {
+            invalid += 1;
+        }
+    }
+
+    println!("{} of {} files conform to the schema", locations.len() - invalid, locations.len());
+    if invalid > 0 {
+        bail!("{} files do not conform to the schema", invalid);
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &ParseStats) {
+    for (version, files) in &stats.feed_versions {
+        println!("Feed version {}: {} files", version, files);
+    }
+    for warning in &stats.warnings {
+        println!("WARNING: {}", warning);
+    }
+    for (fare_type, count) in &stats.unknown_fare_types {
+        println!("Unmapped fare type {:?}: {} occurrences", fare_type, count);
+    }
+    for (reason, count) in &stats.filtered {
+        println!("Filtered {} records with {}", count, reason);
+    }
+    let control = &stats.control;
+    println!(
+        "Read {} transactions, {} documents, {} coupons; {} records",
+        control.transactions, control.documents, control.coupons, control.records
+    );
+}
+
+// Optional stages applied to every record between the parser and the sinks
+struct Enrichment {
+    fx: Option<FxConverter>,
+    refdata: Option<ReferenceData>,
+    dates: Option<DateNormalizer>,
+    // applied after the other stages, to every output table
+    #[cfg(feature = "protect")]
+    protector: FieldProtector,
+    // runs last and only for the record CSV output, once per file (once per run or
+    // worker object with deduplication); load refuses it next to other record sinks
+    #[cfg(feature = "sql")]
+    transform: Option<SqlTransform>,
+}
+
+impl Enrichment {
+    async fn load<S: Storage>(storage: &S) -> Result<Self> {
+        let fx = if config::FX_ENABLED {
+            Some(
+                FxConverter::load(
+                    storage,
+                    config::REPORTING_CURRENCY,
+                    match config::FX_RATE_FILE {
+                        Some(location) => FxRateSource::RateFile(location),
+                        None => FxRateSource::FeedRoe,
+                    },
+                    config::FX_MAX_LOOKBACK_DAYS,
+                    config::ACCOUNTING_CURRENCY,
+                    config::DATE_INPUT_FORMATS,
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        let refdata = if config::REFDATA_AIRPORTS.is_some()
+            || config::REFDATA_CARRIERS.is_some()
+            || config::REFDATA_AGENCIES.is_some()
+        {
+            Some(
+                ReferenceData::load(
+                    storage,
+                    config::REFDATA_AIRPORTS,
+                    config::REFDATA_CARRIERS,
+                    config::REFDATA_AGENCIES,
+                    HaulThresholds {
+                        short_max_km: config::SHORT_HAUL_MAX_KM,
+                        medium_max_km: config::MEDIUM_HAUL_MAX_KM,
+                    },
+                )
+                .await?,
+            )
+        } else {
+            None
+        };
+
+        let dates = if config::DATE_NORMALIZATION_ENABLED {
+            Some(DateNormalizer::new(
+                config::DATE_INPUT_FORMATS,
+                config::DATETIME_OUTPUT_FORMAT,
+                config::DATE_OUTPUT_FORMAT,
+                config::DATE_CONVERT_TO_UTC,
+            ))
+        } else {
+            None
+        };
+
+        #[cfg(feature = "protect")]
+        let protector =
+            FieldProtector::load(storage, config::PII_PROTECTION, config::PII_KEY_ENV, config::PII_KEY_FILE).await?;
+        #[cfg(feature = "protect")]
+        if config::DEDUPE_ENABLED {
+            protector.check_key("DEDUPE_KEY", config::DEDUPE_KEY)?;
+        }
+        #[cfg(all(feature = "protect", feature = "pg"))]
+        if config::PG_ENABLED && config::PG_TARGET_TABLE.is_some() {
+            protector.check_key("PG natural key", &crate::pgsink::NATURAL_KEY)?;
+        }
+
+        // the query only feeds the record CSV, other record sinks would disagree with it
+        if let Some(location) = config::SQL_TRANSFORM_FILE {
+            if config::PG_ENABLED {
+                bail!("SQL transform {} only applies to the record CSV output, disable the PostgreSQL sink", location);
+            }
+            if config::SUMMARY_ENABLED {
+                bail!("SQL transform {} only applies to the record CSV output, disable the daily summary", location);
+            }
+        }
+
+        #[cfg(feature = "sql")]
+        let transform = match config::SQL_TRANSFORM_FILE {
+            Some(location) => Some(
+                SqlTransform::load(storage, location, config::SQL_TABLE_NAME, config::ARROW_BATCH_SIZE).await?,
+            ),
+            None => None,
+        };
+        #[cfg(not(feature = "sql"))]
+        if let Some(location) = config::SQL_TRANSFORM_FILE {
+            bail!("SQL transform {} is configured but the \"sql\" feature is not enabled", location);
+        }
+
+        Ok(Self {
+            fx,
+            refdata,
+            dates,
+            #[cfg(feature = "protect")]
+            protector,
+            #[cfg(feature = "sql")]
+            transform,
+        })
+    }
+
+    fn apply(&self, rec: &mut Record) {
+        if let Some(fx) = &self.fx {
+            fx.convert(rec);
+        }
+        if let Some(refdata) = &self.refdata {
+            refdata.enrich(rec);
+        }
+        if let Some(dates) = &self.dates {
+            dates.normalize(rec, self.refdata.as_ref());
+        }
+        self.protect(rec);
+    }
+
+    fn protect<T: TextColumns>(&self, row: &mut T) {
+        #[cfg(feature = "protect")]
+        self.protector.protect(row);
+        #[cfg(not(feature = "protect"))]
+        let _ = row;
+    }
+
+    fn protect_normalized(&self, feed: &mut NormalizedFeed) {
+        feed.documents.iter_mut().for_each(|row| self.protect(row));
+        feed.coupons.iter_mut().for_each(|row| self.protect(row));
+        feed.fares.iter_mut().for_each(|row| self.protect(row));
+        feed.taxes.iter_mut().for_each(|row| self.protect(row));
+        feed.commissions.iter_mut().for_each(|row| self.protect(row));
+    }
+
+    // protection settings for the run manifest, null without the "protect" feature
+    fn describe_protection(&self) -> serde_json::Value {
+        #[cfg(feature = "protect")]
+        let description = self.protector.describe();
+        #[cfg(not(feature = "protect"))]
+        let description = serde_json::Value::Null;
+        description
+    }
+
+    fn has_transform(&self) -> bool {
+        #[cfg(feature = "sql")]
+        let configured = self.transform.is_some();
+        #[cfg(not(feature = "sql"))]
+        let configured = false;
+        configured
+    }
+
+    // Writes records to the record CSV series, through the SQL transform if one is configured
+    async fn write_records<S: Storage>(&self, writer: &mut CsvChunkerWriter<S>, records: Vec<Record>) -> Result<()> {
+        #[cfg(feature = "sql")]
+        if let Some(transform) = &self.transform {
+            for row in transform.apply_rows(&records).await? {
+                writer.write_record(&row).await?;
+            }
+            return Ok(());
+        }
+
+        for rec in records {
+            writer.write_record(&rec).await?;
+        }
+        Ok(())
+    }
+
+    fn print_summary(&self) {
+        if let Some(refdata) = &self.refdata {
+            for (dataset, codes) in refdata.misses() {
+                let total: usize = codes.values().sum();
+                println!("Reference data misses in {}: {} lookups, {} unknown codes", dataset, total, codes.len());
+            }
+        }
+    }
+}
+
+// The chunked CSV outputs of a run: the flat records and, if enabled, the tax and
+// linkage tables, the normalized tables and the daily summary
+struct ChunkWriters<S: Storage> {
+    records: CsvChunkerWriter<S>,
+    taxes: Option<CsvChunkerWriter<S>>,
+    links: Option<CsvChunkerWriter<S>>,
+    normalized: Option<NormalizedWriters<S>>,
+    summary: Option<DailySummary<S>>,
+}
+
+impl<S: Storage + Clone> ChunkWriters<S> {
+    // `suffix` is appended to every prefix so separate series never share an S3 key
+    async fn new(storage: &S, suffix: &str, timestamp: &str) -> Result<Self> {
+        let records = chunk_writer(storage, config::CSV_PREFIX, suffix, timestamp).await?;
+
+        let taxes = if config::TAX_OUTPUT_ENABLED {
+            Some(chunk_writer(storage, config::TAX_CSV_PREFIX, suffix, timestamp).await?)
+        } else {
+            None
+        };
+
+        let links = if config::LINK_OUTPUT_ENABLED {
+            Some(chunk_writer(storage, config::LINK_CSV_PREFIX, suffix, timestamp).await?)
+        } else {
+            None
+        };
+
+        let normalized = if config::NORMALIZED_OUTPUT_ENABLED {
+            Some(NormalizedWriters::new(storage, suffix, timestamp).await?)
+        } else {
+            None
+        };
+
+        let summary = if config::SUMMARY_ENABLED {
+            Some(DailySummary::new(
+                &format!("{}{}", config::SUMMARY_PREFIX, suffix),
+                config::OUTPUT_BUCKET,
+                storage.clone(),
+                timestamp,
+                config::SUMMARY_DATE_COLUMN,
+                config::SUMMARY_DIMENSIONS,
+                config::SUMMARY_MEASURES,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self { records, taxes, links, normalized, summary })
+    }
+
+    // input key recorded in the metadata of the chunks the next rows land in
+    fn set_source(&mut self, key: &str) {
+        self.records.set_source(key);
+        for writer in [self.taxes.as_mut(), self.links.as_mut()].into_iter().flatten() {
+            writer.set_source(key);
+        }
+        if let Some(tables) = self.normalized.as_mut() {
+            for writer in [
+                &mut tables.documents,
+                &mut tables.coupons,
+                &mut tables.fares,
+                &mut tables.taxes,
+                &mut tables.commissions,
+            ] {
+                writer.set_source(key);
+            }
+        }
+    }
+
+    // tax, link and normalized rows, each to its table when that output is enabled
+    async fn write_side_rows(
+        &mut self,
+        taxes: &[TaxRecord],
+        links: &[DocumentLinkRecord],
+        normalized: Option<&NormalizedFeed>,
+    ) -> Result<()> {
+        if let Some(tax_writer) = self.taxes.as_mut() {
+            for tax in taxes {
+                tax_writer.write_record(tax).await?;
+            }
+        }
+        if let Some(link_writer) = self.links.as_mut() {
+            for link in links {
+                link_writer.write_record(link).await?;
+            }
+        }
+        if let Some(tables) = self.normalized.as_mut()
+            && let Some(normalized) = normalized
+        {
+            tables.write(normalized).await?;
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        self.records.finalize().await?;
+        if let Some(taxes) = self.taxes.as_mut() {
+            taxes.finalize().await?;
+        }
+        if let Some(links) = self.links.as_mut() {
+            links.finalize().await?;
+        }
+        if let Some(tables) = self.normalized.as_mut() {
+            tables.finalize().await?;
+        }
+        if let Some(summary) = self.summary.as_mut() {
+            summary.finalize().await?;
+        }
+        Ok(())
+    }
+}
+
+// One chunked sink per normalized table, plus the surrogate key counters they share
+struct NormalizedWriters<S: Storage> {
+    keys: SurrogateKeys,
+    documents: CsvChunkerWriter<S>,
+    coupons: CsvChunkerWriter<S>,
+    fares: CsvChunkerWriter<S>,
+    taxes: CsvChunkerWriter<S>,
+    commissions: CsvChunkerWriter<S>,
+}
+
+impl<S: Storage + Clone> NormalizedWriters<S> {
+    async fn new(storage: &S, suffix: &str, timestamp: &str) -> Result<Self> {
+        Ok(Self {
+            keys: SurrogateKeys::default(),
+            documents: chunk_writer(storage, config::DOCUMENT_CSV_PREFIX, suffix, timestamp).await?,
+            coupons: chunk_writer(storage, config::COUPON_CSV_PREFIX, suffix, timestamp).await?,
+            fares: chunk_writer(storage, config::FARE_CSV_PREFIX, suffix, timestamp).await?,
+            taxes: chunk_writer(storage, config::TAX_TABLE_CSV_PREFIX, suffix, timestamp).await?,
+            commissions: chunk_writer(storage, config::COMMISSION_CSV_PREFIX, suffix, timestamp).await?,
+        })
+    }
+
+    async fn write(&mut self, feed: &NormalizedFeed) -> Result<()> {
+        for row in &feed.documents {
+            self.documents.write_record(row).await?;
+        }
+        for row in &feed.coupons {
+            self.coupons.write_record(row).await?;
+        }
+        for row in &feed.fares {
+            self.fares.write_record(row).await?;
+        }
+        for row in &feed.taxes {
+            self.taxes.write_record(row).await?;
+        }
+        for row in &feed.commissions {
+            self.commissions.write_record(row).await?;
+        }
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        self.documents.finalize().await?;
+        self.coupons.finalize().await?;
+        self.fares.finalize().await?;
+        self.taxes.finalize().await?;
+        self.commissions.finalize().await?;
+        Ok(())
+    }
+}
+
+async fn chunk_writer<S: Storage + Clone>(
+    storage: &S,
+    prefix: &str,
+    suffix: &str,
+    timestamp: &str,
+) -> Result<CsvChunkerWriter<S>> {
+    CsvChunkerWriter::new(
+        &format!("{}{}", prefix, suffix),
+        config::OUTPUT_BUCKET,
+        config::MAX_ROWS_PER_FILE,
+        storage.clone(),
+        timestamp,
+    )
+    .await
+}
+
+// optional XSD check of every input before it is parsed
+#[cfg(feature = "validate")]
+async fn make_validator<S: Storage>(storage: &S) -> Result<Option<XsdValidator>> {
+    if !config::XSD_VALIDATION_ENABLED {
+        return Ok(None);
+    }
+    Ok(Some(XsdValidator::load(storage, config::XSD_LOCATION).await?))
+}
+
+#[cfg(not(feature = "validate"))]
+async fn make_validator<S: Storage>(_storage: &S) -> Result<Option<XsdValidator>> {
+    if config::XSD_VALIDATION_ENABLED {
+        bail!("XSD validation is enabled but the \"validate\" feature is not");
+    }
+    Ok(None)
+}
+
+// XML keys under a prefix
+async fn list_xml_objects<S: Storage>(storage: &S, bucket: &str, prefix: &str) -> Result<Vec<ObjectInfo>> {
+    let objects = storage.list_objects(bucket, prefix).await?;
+    Ok(objects.into_iter().filter(|o| is_input_key(&o.key)).collect())
+}
+
+// XML objects, except the copies quarantined into the input bucket: in worker mode their
+// upload is announced like any other and would be quarantined again, over and over
+fn is_input_key(key: &str) -> bool {
+    key.to_lowercase().ends_with(".xml") && !key.starts_with(config::QUARANTINE_PREFIX)
+}
+
+// Rolls back the open file of the PostgreSQL sink when `result` is an error, so the
+// connection is usable for the next file; `result` is passed through
+async fn abort_on_error<T>(pg_sink: Option<&mut PgSink>, result: Result<T>) -> Result<T> {
+    if result.is_err()
+        && let Some(pg) = pg_sink
+        && let Err(e) = pg.abort_file().await
+    {
+        crate::diagnostics::log_error("PostgreSQL rollback failed", &e);
+    }
+    result
+}
+
+// Keys written by earlier runs, empty without DEDUPE_STATE_KEY; None when dedupe is off
+async fn load_dedupe_state<S: Storage>(storage: &S) -> Result<Option<DedupeState>> {
+    if !config::DEDUPE_ENABLED {
+        return Ok(None);
+    }
+    let mut state = match config::DEDUPE_STATE_KEY {
+        Some(key) => DedupeState::load(storage, config::OUTPUT_BUCKET, key).await?,
+        None => DedupeState::default(),
+    };
+    let expired = expire_dedupe_state(&mut state);
+    if expired > 0 {
+        println!("Dropped {} dedupe keys older than {:?} days", expired, config::DEDUPE_STATE_RETENTION_DAYS);
+    }
+    Ok(Some(state))
+}
+
+async fn save_dedupe_state<S: Storage>(storage: &S, state: &mut DedupeState) -> Result<()> {
+    if let Some(key) = config::DEDUPE_STATE_KEY {
+        expire_dedupe_state(state);
+        state.save(storage, config::OUTPUT_BUCKET, key).await?;
+    }
+    Ok(())
+}
+
+// Applies DEDUPE_STATE_RETENTION_DAYS, counted back from now
+fn expire_dedupe_state(state: &mut DedupeState) -> usize {
+    match config::DEDUPE_STATE_RETENTION_DAYS {
+        Some(days) => state.expire_before(chrono::Utc::now().naive_utc() - chrono::Duration::days(days)),
+        None => 0,
+    }
+}
+
+fn make_dedupe_stage(state: DedupeState) -> Result<DedupeStage> {
+    let policy = match config::DEDUPE_STATUS_PRECEDENCE {
+        Some(statuses) => DedupePolicy::DocumentStatus(statuses.iter().map(|s| s.to_string()).collect()),
+        None => DedupePolicy::LatestTimestamp,
+    };
+    let stage = DedupeStage::new(
+        config::DEDUPE_KEY,
+        policy,
+        config::DATE_INPUT_FORMATS,
+        config::DEDUPE_MAX_IN_MEMORY,
+        config::DEDUPE_SPILL_DIR,
+    )?;
+    Ok(stage.with_state(state))
+}
+
+// optional PostgreSQL sink, loaded with COPY in one transaction per source file
+#[cfg(feature = "pg")]
+async fn make_pg_sink() -> Result<Option<PgSink>> {
+    if !config::PG_ENABLED {
+        return Ok(None);
+    }
+
+    let conn_str = std::env::var(config::PG_CONNECTION_ENV)?;
+    let sink = PgSink::connect(
+        &conn_str,
+        config::PG_STAGING_TABLE,
+        config::PG_TARGET_TABLE,
+        config::PG_BATCH_SIZE,
+    )
+    .await?;
+
+    Ok(Some(sink))
+}
+
+#[cfg(not(feature = "pg"))]
+async fn make_pg_sink() -> Result<Option<PgSink>> {
+    if config::PG_ENABLED {
+        bail!("the PostgreSQL sink is enabled but the \"pg\" feature is not");
+    }
+    Ok(None)
+}
+
+// File name of an S3 key without extension, reduced to characters safe for local paths
+#[cfg(feature = "aws")]
+fn object_stem(key: &str) -> String {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let stem = name.rsplit_once('.').map(|(s, _)| s).unwrap_or(name);
+    stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
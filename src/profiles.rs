@@ -0,0 +1,299 @@
+use anyhow::{Result, bail};
+use quick_xml::events::BytesStart;
+use std::collections::HashMap;
+
+use crate::parser::get_attr_val;
+
+/// Root name the parsers match against, whatever the feed actually uses
+pub const CANONICAL_ROOT: &str = "AMA_REV.Feed";
+
+/// How a schema problem is handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strictness {
+    Fail,
+    Warn,
+}
+
+/// Mapping profile for one family of feed versions
+#[derive(Clone, Copy, Debug)]
+pub struct FeedProfile {
+    pub name: &'static str,
+    pub root: &'static str,
+    /// accepted values of the root Version attribute or of its default namespace
+    pub versions: &'static [&'static str],
+    /// elements between the root and Transaction that are skipped when matching paths
+    pub wrappers: &'static [&'static str],
+}
+
+/// Interned tag name. The names the parsers match on have the fixed ids of [`tags`];
+/// any other name gets the next free id of the [`FeedPath`] that met it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TagId(u32);
+
+macro_rules! known_tags {
+    ($($id:ident = $name:literal,)*) => {
+        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+        #[repr(u32)]
+        enum KnownTag {
+            $($id,)*
+        }
+
+        /// Ids of the tag names the parsers match on, usable in slice patterns over
+        /// [`FeedPath::segments`]
+        pub mod tags {
+            use super::{KnownTag, TagId};
+
+            $(pub const $id: TagId = TagId(KnownTag::$id as u32);)*
+        }
+
+        const KNOWN_TAGS: &[&str] = &[$($name),*];
+    };
+}
+
+known_tags! {
+    AMA_REV_FEED = "AMA_REV.Feed",
+    TRANSACTION = "Transaction",
+    EVENT = "Event",
+    ENTITY_STATUS = "EntityStatus",
+    DOCUMENT = "Document",
+    ISSUANCE_DETAILS = "IssuanceDetails",
+    PRICING_DETAILS = "PricingDetails",
+    CURRENCY_OF_PAYMENT = "CurrencyOfPayment",
+    TOUR_CODE = "TourCode",
+    REVENUE_ATTRIBUTABLE_AGENT = "RevenueAttributableAgent",
+    BOOKING_INFORMATION = "BookingInformation",
+    PNR_IDENTIFICATION = "PNRIdentification",
+    AMADEUS_RECORD_LOCATOR = "AmadeusRecordLocator",
+    ID = "ID",
+    FARES = "Fares",
+    FARE = "Fare",
+    ACCOUNTABLE_ENTITY = "AccountableEntity",
+    AMOUNT = "Amount",
+    AMOUNT_TYPE = "AmountType",
+    ROE = "ROE",
+    STANDARD_COMMISSION = "StandardCommission",
+    SUPPLEMENTARY_COMMISSION = "SupplementaryCommission",
+    COMMISSION = "Commission",
+    REFERENCED_DOCUMENTS = "ReferencedDocuments",
+    REFERENCED_DOCUMENT = "ReferencedDocument",
+    REFERENCED_COUPON = "ReferencedCoupon",
+    COUPON = "Coupon",
+    SEGMENT_INFO = "SegmentInfo",
+    COMPANY_DETAILS = "CompanyDetails",
+    MARKETING_CARRIER = "MarketingCarrier",
+    OPERATING_CARRIER = "OperatingCarrier",
+    CLASS_DETAILS = "ClassDetails",
+    BOOKING_CLASS = "BookingClass",
+    OPERATING_CABIN_CLASS = "OperatingCabinClass",
+    FLIGHT_IDENTIFICATION = "FlightIdentification",
+    OPERATING_FLIGHT_NUMBER = "OperatingFlightNumber",
+    FLIGHT_NUMBER = "FlightNumber",
+    COUPON_DETAILS = "CouponDetails",
+    FARE_BASIS_CODE = "FareBasisCode",
+    CALCULATED_AMOUNTS = "CalculatedAmounts",
+    COUPON_PRORATED_FARE = "CouponProratedFare",
+    COUPON_TAXES = "CouponTaxes",
+    COLLECTED_TAXES_CPN_LVL = "CollectedTaxesCpnLvl",
+    TAX = "Tax",
+    COUPON_STANDARD_COMMISSION = "CouponStandardCommission",
+}
+
+/// Element path of the current event, rooted at CANONICAL_ROOT. The first element
+/// selects the profile; wrapper elements of that profile are left out of the path.
+/// Tag names are interned to ids per path, so tracking it allocates nothing once every
+/// distinct tag of the file was seen, and the table goes away with the parser.
+pub struct FeedPath<'p> {
+    profiles: &'p [FeedProfile],
+    version_policy: Strictness,
+    tags: TagInterner,
+    segments: Vec<TagId>,
+    pushed: Vec<bool>,
+    profile: Option<&'p FeedProfile>,
+    version: String,
+    warnings: Vec<String>,
+}
+
+impl<'p> FeedPath<'p> {
+    pub fn new(profiles: &'p [FeedProfile], version_policy: Strictness) -> Self {
+        Self {
+            profiles,
+            version_policy,
+            tags: TagInterner::new(),
+            segments: Vec::new(),
+            pushed: Vec::new(),
+            profile: None,
+            version: String::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Enters an element (Start or Empty event)
+    pub fn open(&mut self, e: &BytesStart) -> Result<()> {
+        let tag = self.tags.intern(e.local_name().as_ref());
+
+        if self.pushed.is_empty() {
+            let root = self.tags.name(tag).to_string();
+            self.detect(&root, e)?;
+            self.segments.push(tags::AMA_REV_FEED);
+            self.pushed.push(true);
+            return Ok(());
+        }
+
+        let is_wrapper = self.segments.len() == 1
+            && self.profile.is_some_and(|p| p.wrappers.contains(&self.tags.name(tag)));
+        if is_wrapper {
+            self.pushed.push(false);
+        } else {
+            self.segments.push(tag);
+            self.pushed.push(true);
+        }
+        Ok(())
+    }
+
+    /// Leaves the innermost element (End event, or right after an Empty one)
+    pub fn close(&mut self) {
+        if self.pushed.pop() == Some(true) {
+            self.segments.pop();
+        }
+    }
+
+    pub fn segments(&self) -> &[TagId] {
+        &self.segments
+    }
+
+    /// Tag name of an id met by this path
+    pub fn name(&self, tag: TagId) -> &str {
+        self.tags.name(tag)
+    }
+
+    /// Names of the current path, root first
+    pub fn names(&self) -> Vec<&str> {
+        self.segments.iter().map(|t| self.tags.name(*t)).collect()
+    }
+
+    /// Names of the current path below the root, for matching configured paths
+    pub fn below_root(&self) -> impl Iterator<Item = &str> + Clone {
+        self.segments.iter().skip(1).map(|t| self.tags.name(*t))
+    }
+
+    /// `"<profile> <version>"` of the file, once its root element was read
+    pub fn detected(&self) -> Option<String> {
+        self.profile.map(|p| format!("{} {}", p.name, self.version))
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn detect(&mut self, root: &str, e: &BytesStart) -> Result<()> {
+        let version = get_attr_val(e, b"Version");
+        let namespace = default_namespace(e);
+
+        // the Version attribute identifies the file, the namespace is the fallback
+        self.version = if version.is_empty() { namespace.clone() } else { version.clone() };
+        self.profile = self.profiles.iter().find(|p| {
+            p.root == root
+                && (p.versions.contains(&self.version.as_str())
+                    || (!namespace.is_empty() && p.versions.contains(&namespace.as_str())))
+        });
+
+        if self.profile.is_none() {
+            let msg = format!(
+                "unsupported feed: root {:?}, version {:?}, namespace {:?}",
+                root, version, namespace
+            );
+            if self.version_policy == Strictness::Fail {
+                bail!(msg);
+            }
+            self.warnings.push(msg);
+            // best effort: keep the wrappers of a profile with the same root
+            self.profile = self.profiles.iter().find(|p| p.root == root);
+        }
+        Ok(())
+    }
+}
+
+// Tag names of one parser: the known tags first, then every other name in the order
+// it was met. Only names that occur in the parsed input are added.
+struct TagInterner {
+    ids: HashMap<Box<[u8]>, TagId>,
+    names: Vec<Box<str>>,
+}
+
+impl TagInterner {
+    fn new() -> Self {
+        let mut interner = Self { ids: HashMap::new(), names: Vec::new() };
+        for name in KNOWN_TAGS {
+            interner.intern(name.as_bytes());
+        }
+        interner
+    }
+
+    fn intern(&mut self, name: &[u8]) -> TagId {
+        if let Some(tag) = self.ids.get(name) {
+            return *tag;
+        }
+        let tag = TagId(self.names.len() as u32);
+        self.names.push(String::from_utf8_lossy(name).into());
+        self.ids.insert(name.into(), tag);
+        tag
+    }
+
+    fn name(&self, tag: TagId) -> &str {
+        &self.names[tag.0 as usize]
+    }
+}
+
+fn default_namespace(e: &BytesStart) -> String {
+    for a in e.attributes().flatten() {
+        if a.key.as_ref() == b"xmlns" {
+            return a.unescape_value().unwrap_or_default().to_string();
+        }
+    }
+    String::new()
+}
+
+/// Tracks which mandatory paths (relative to the root, "/"-separated) a file contained
+pub struct MandatoryPaths {
+    paths: Vec<(String, Vec<String>)>,
+    seen: Vec<bool>,
+}
+
+impl MandatoryPaths {
+    pub fn new(paths: &[String]) -> Self {
+        Self {
+            paths: paths
+                .iter()
+                .map(|p| (p.clone(), p.split('/').map(|s| s.to_string()).collect()))
+                .collect(),
+            seen: vec![false; paths.len()],
+        }
+    }
+
+    pub fn observe(&mut self, path: &FeedPath) {
+        let Some(depth) = path.segments().len().checked_sub(1) else {
+            return;
+        };
+        for (i, (_, parts)) in self.paths.iter().enumerate() {
+            if !self.seen[i] && parts.len() == depth && parts.iter().map(String::as_str).eq(path.below_root()) {
+                self.seen[i] = true;
+            }
+        }
+    }
+
+    /// Combines what separately parsed slices of one file contained
+    pub fn merge(&mut self, other: &MandatoryPaths) {
+        for (seen, other_seen) in self.seen.iter_mut().zip(&other.seen) {
+            *seen |= *other_seen;
+        }
+    }
+
+    pub fn missing(&self) -> Vec<&str> {
+        self.paths
+            .iter()
+            .zip(&self.seen)
+            .filter(|(_, seen)| !**seen)
+            .map(|((p, _), _)| p.as_str())
+            .collect()
+    }
+}
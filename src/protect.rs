@@ -0,0 +1,208 @@
+use aes::Aes256;
+use anyhow::{Context, Result, anyhow, bail};
+use fpe::ff1::{FF1, FlexibleNumeralString};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::models::{Record, TextColumns};
+use crate::storage::{Storage, read_location};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Alphabets of the format-preserving tokenization, the smallest one that fits a value is used
+const DIGITS: &str = "0123456789";
+const ALPHANUMERIC: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// How one column is protected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protection {
+    /// the value is emptied; the column stays so every output keeps its header
+    Drop,
+    /// every character but the last `keep_last` becomes '*'
+    Mask { keep_last: usize },
+    /// hex HMAC-SHA256 of the value under the protection key
+    Hash,
+    /// FF1 format-preserving encryption under the protection key: digits stay digits,
+    /// upper-case alphanumerics stay alphanumerics, and the length is kept. Values
+    /// too short for FF1 or with other characters are hashed instead.
+    Tokenize,
+}
+
+impl Protection {
+    fn describe(&self) -> serde_json::Value {
+        match self {
+            Protection::Drop => serde_json::json!({ "mode": "drop" }),
+            Protection::Mask { keep_last } => serde_json::json!({ "mode": "mask", "keep_last": keep_last }),
+            Protection::Hash => serde_json::json!({ "mode": "hmac-sha256" }),
+            Protection::Tokenize => serde_json::json!({ "mode": "ff1" }),
+        }
+    }
+}
+
+/// Rewrites the configured columns of every output row. Hash and Tokenize are
+/// deterministic, so protected values still join and deduplicate.
+pub struct FieldProtector {
+    rules: Vec<(String, Protection)>,
+    key: Option<Vec<u8>>,
+    key_source: Option<String>,
+    ff1_digits: Option<FF1<Aes256>>,
+    ff1_alphanumeric: Option<FF1<Aes256>>,
+}
+
+impl FieldProtector {
+    pub fn new(rules: &[(&str, Protection)], key: Option<Vec<u8>>, key_source: Option<String>) -> Result<Self> {
+        for (column, protection) in rules {
+            if !Record::COLUMNS.contains(column) {
+                bail!("protected column {:?} is not a Record column", column);
+            }
+            if matches!(protection, Protection::Hash | Protection::Tokenize) && key.is_none() {
+                bail!("protecting {:?} with {:?} needs a protection key", column, protection);
+            }
+        }
+
+        // FF1 runs on AES-256, its key is derived from the protection key
+        let (ff1_digits, ff1_alphanumeric) = match &key {
+            Some(key) => {
+                let aes_key = hmac(key, b"xmlpoc ff1 key");
+                (
+                    Some(FF1::new(&aes_key, DIGITS.len() as u32).map_err(|e| anyhow!("{:?}", e))?),
+                    Some(FF1::new(&aes_key, ALPHANUMERIC.len() as u32).map_err(|e| anyhow!("{:?}", e))?),
+                )
+            }
+            None => (None, None),
+        };
+
+        Ok(Self {
+            rules: rules.iter().map(|(column, protection)| (column.to_string(), *protection)).collect(),
+            key,
+            key_source,
+            ff1_digits,
+            ff1_alphanumeric,
+        })
+    }
+
+    /// Fails if a column of `key` (named `purpose` in the error) is dropped or masked:
+    /// rows run through protection before they are deduplicated or upserted, and
+    /// distinct keys would merge. Hash and Tokenize keep distinct values apart.
+    pub fn check_key(&self, purpose: &str, key: &[&str]) -> Result<()> {
+        for (column, protection) in &self.rules {
+            if key.contains(&column.as_str()) && matches!(protection, Protection::Drop | Protection::Mask { .. }) {
+                bail!("{} column {:?} cannot be protected with {:?}, use Hash or Tokenize", purpose, column, protection);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the key from the environment variable `key_env` or, if it is unset, from
+    /// `key_file` (local path or s3://). Surrounding whitespace of the key is ignored.
+    pub async fn load<S: Storage>(
+        storage: &S,
+        rules: &[(&str, Protection)],
+        key_env: &str,
+        key_file: Option<&str>,
+    ) -> Result<Self> {
+        let (key, key_source) = match (std::env::var(key_env), key_file) {
+            (Ok(key), _) => (Some(key.trim().as_bytes().to_vec()), Some(format!("env {}", key_env))),
+            (Err(_), Some(location)) => {
+                let data = read_location(storage, location)
+                    .await
+                    .with_context(|| format!("loading protection key {}", location))?;
+                let key = String::from_utf8(data).context("protection key is not UTF-8")?;
+                (Some(key.trim().as_bytes().to_vec()), Some(format!("file {}", location)))
+            }
+            (Err(_), None) => (None, None),
+        };
+        if key.as_ref().is_some_and(|k| k.is_empty()) {
+            bail!("protection key from {} is empty", key_source.unwrap_or_default());
+        }
+        Self::new(rules, key, key_source)
+    }
+
+    /// Protects the configured columns a row has; the others are left alone
+    pub fn protect<T: TextColumns>(&self, row: &mut T) {
+        for (column, protection) in &self.rules {
+            if let Some(value) = row.field_mut(column)
+                && !value.is_empty()
+            {
+                *value = self.protect_value(value, *protection);
+            }
+        }
+    }
+
+    fn protect_value(&self, value: &str, protection: Protection) -> String {
+        match protection {
+            Protection::Drop => String::new(),
+            Protection::Mask { keep_last } => {
+                let count = value.chars().count();
+                value
+                    .chars()
+                    .enumerate()
+                    .map(|(i, c)| if i + keep_last < count { '*' } else { c })
+                    .collect()
+            }
+            Protection::Hash => self.hash(value),
+            Protection::Tokenize => self.tokenize(value).unwrap_or_else(|| self.hash(value)),
+        }
+    }
+
+    fn hash(&self, value: &str) -> String {
+        let key = self.key.as_deref().unwrap_or_default();
+        to_hex(&hmac(key, value.as_bytes()))
+    }
+
+    fn tokenize(&self, value: &str) -> Option<String> {
+        let (ff1, alphabet) = if value.chars().all(|c| c.is_ascii_digit()) {
+            (self.ff1_digits.as_ref()?, DIGITS)
+        } else {
+            (self.ff1_alphanumeric.as_ref()?, ALPHANUMERIC)
+        };
+
+        let numerals = value
+            .chars()
+            .map(|c| alphabet.find(c).map(|i| i as u16))
+            .collect::<Option<Vec<u16>>>()?;
+        let encrypted = ff1.encrypt(&[], &FlexibleNumeralString::from(numerals)).ok()?;
+        Some(
+            Vec::<u16>::from(encrypted)
+                .into_iter()
+                .map(|n| alphabet.as_bytes()[n as usize] as char)
+                .collect(),
+        )
+    }
+
+    /// The settings for the run manifest: columns and modes, where the key came from
+    /// and a fingerprint that tells keys apart without revealing them. Null when no
+    /// column is protected.
+    pub fn describe(&self) -> serde_json::Value {
+        if self.rules.is_empty() {
+            return serde_json::Value::Null;
+        }
+        let columns: Vec<serde_json::Value> = self
+            .rules
+            .iter()
+            .map(|(column, protection)| {
+                let mut entry = protection.describe();
+                entry["column"] = column.clone().into();
+                entry
+            })
+            .collect();
+        let key_id = self.key.as_ref().map(|key| to_hex(&hmac(key, b"xmlpoc key id"))[..16].to_string());
+
+        serde_json::json!({
+            "columns": columns,
+            "key_source": self.key_source,
+            "key_id": key_id,
+        })
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC key of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
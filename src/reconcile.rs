@@ -0,0 +1,102 @@
+use anyhow::{Result, bail};
+
+use crate::models::ControlCounts;
+use crate::profiles::Strictness;
+
+// Largest difference between two amount sums that still counts as equal
+const AMOUNT_TOLERANCE: f64 = 0.005;
+
+/// A control total the feed may carry as an attribute, e.g. a trailer element with the
+/// number of transactions in the file
+#[derive(Clone, Copy, Debug)]
+pub struct ControlTotal {
+    /// "transactions", "documents", "coupons" or "accounted_fare_amount"
+    pub metric: &'static str,
+    /// element path below the root, "" for the root element itself
+    pub path: &'static str,
+    pub attribute: &'static str,
+}
+
+impl ControlTotal {
+    /// Whether the element at `path` (below the root) carries this total
+    pub fn matches<'a>(&self, path: impl Iterator<Item = &'a str>) -> bool {
+        self.path.split('/').filter(|s| !s.is_empty()).eq(path)
+    }
+}
+
+/// What went to the output for one file or run
+#[derive(Clone, Debug, Default)]
+pub struct OutputCounts {
+    /// rows the record sink reports it wrote, None where it is not comparable
+    /// (deduplication or a SQL transform change the rows)
+    pub rows_written: Option<u64>,
+    /// sum of cpn_far_fare_amount_accounting_currency over the rows written, None
+    /// where the rows are not comparable
+    pub fare_amount: Option<f64>,
+}
+
+/// Compares the parse-time counts with each other, with the control totals the feed
+/// declares and with the output. Returns one message per mismatch.
+pub fn reconcile(control: &ControlCounts, filtered: u64, output: &OutputCounts) -> Vec<String> {
+    let mut mismatches = Vec::new();
+
+    if control.transactions != control.records + filtered {
+        mismatches.push(format!(
+            "{} Transaction elements but {} records and {} filtered",
+            control.transactions, control.records, filtered
+        ));
+    }
+
+    if let Some(rows) = output.rows_written
+        && rows != control.records
+    {
+        mismatches.push(format!("{} records parsed but {} rows written", control.records, rows));
+    }
+
+    if let Some(fare_amount) = output.fare_amount
+        && !amounts_equal(control.kept_accounted_fare_amount, fare_amount)
+    {
+        mismatches.push(format!(
+            "accounted coupon fares sum to {} but the records carry {}",
+            round(control.kept_accounted_fare_amount),
+            round(fare_amount)
+        ));
+    }
+
+    for (metric, declared) in &control.declared {
+        let counted = match metric.as_str() {
+            "transactions" => control.transactions as f64,
+            "documents" => control.documents as f64,
+            "coupons" => control.coupons as f64,
+            "accounted_fare_amount" => control.accounted_fare_amount,
+            other => {
+                mismatches.push(format!("unknown control total {:?}", other));
+                continue;
+            }
+        };
+        if !amounts_equal(*declared, counted) {
+            mismatches.push(format!("feed declares {} {} but {} were counted", declared, metric, round(counted)));
+        }
+    }
+
+    mismatches
+}
+
+/// Prints the mismatches of `scope` (a key or "run") and fails under Strictness::Fail
+pub fn report(scope: &str, mismatches: &[String], policy: Strictness) -> Result<()> {
+    for mismatch in mismatches {
+        println!("RECONCILIATION {}: {}", scope, mismatch);
+    }
+    if !mismatches.is_empty() && policy == Strictness::Fail {
+        bail!("{} does not reconcile: {}", scope, mismatches.join("; "));
+    }
+    Ok(())
+}
+
+fn amounts_equal(a: f64, b: f64) -> bool {
+    (a - b).abs() < AMOUNT_TOLERANCE
+}
+
+fn round(amount: f64) -> f64 {
+    (amount * 1e6).round() / 1e6
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+#[cfg(feature = "timezones")]
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::models::Record;
+use crate::storage::Storage;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Airport {
+    pub code: String,
+    pub country: String,
+    pub region: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub timezone: Option<String>, // IANA name, e.g. Europe/Berlin
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Carrier {
+    pub code: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Agency {
+    pub iata: String,
+    pub name: String,
+    pub country: String,
+}
+
+/// Distance thresholds (great-circle km) separating short, medium and long haul
+#[derive(Clone, Copy, Debug)]
+pub struct HaulThresholds {
+    pub short_max_km: f64,
+    pub medium_max_km: f64,
+}
+
+pub struct ReferenceData {
+    airports: HashMap<String, Airport>,
+    carriers: HashMap<String, Carrier>,
+    agencies: HashMap<String, Agency>,
+    haul: HaulThresholds,
+    // dataset -> unknown code -> lookups that missed
+    misses: Mutex<BTreeMap<&'static str, BTreeMap<String, usize>>>,
+}
+
+impl ReferenceData {
+    /// Loads every configured dataset, a None location leaves that dataset empty
+    pub async fn load<S: Storage>(
+        storage: &S,
+        airports: Option<&str>,
+        carriers: Option<&str>,
+        agencies: Option<&str>,
+        haul: HaulThresholds,
+    ) -> Result<Self> {
+        let airports: Vec<Airport> = load_dataset(storage, airports).await?;
+        let carriers: Vec<Carrier> = load_dataset(storage, carriers).await?;
+        let agencies: Vec<Agency> = load_dataset(storage, agencies).await?;
+
+        Ok(Self {
+            airports: airports.into_iter().map(|a| (a.code.clone(), a)).collect(),
+            carriers: carriers.into_iter().map(|c| (c.code.clone(), c)).collect(),
+            agencies: agencies.into_iter().map(|a| (a.iata.clone(), a)).collect(),
+            haul,
+            misses: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn enrich(&self, rec: &mut Record) {
+        let (origin_code, dest_code) = split_segment(&rec.segment);
+        let origin = self.airport(origin_code);
+        let dest = self.airport(dest_code);
+
+        if let Some(a) = origin {
+            rec.origin_country = a.country.clone();
+            rec.origin_region = a.region.clone();
+        }
+        if let Some(a) = dest {
+            rec.destination_country = a.country.clone();
+            rec.destination_region = a.region.clone();
+        }
+        rec.haul_type = match (origin, dest) {
+            (Some(o), Some(d)) => self.haul_type(o, d).unwrap_or_default(),
+            _ => String::new(),
+        };
+
+        rec.marketting_carrier_name = self.carrier_name(&rec.marketting_carrier);
+        rec.operating_carrier_name = self.carrier_name(&rec.operating_carrier);
+        rec.validating_carrier_name = self.carrier_name(&rec.validating_carrier);
+
+        if let Some(agency) = self.lookup("agencies", &self.agencies, &rec.iata) {
+            rec.agency_name = agency.name.clone();
+            rec.agency_country = agency.country.clone();
+        }
+    }
+
+    pub fn airport(&self, code: &str) -> Option<&Airport> {
+        self.lookup("airports", &self.airports, code)
+    }
+
+    /// Timezone of an airport; misses were already counted by enrich
+    #[cfg(feature = "timezones")]
+    pub fn airport_timezone(&self, code: &str) -> Option<Tz> {
+        self.airports.get(code)?.timezone.as_deref()?.parse::<Tz>().ok()
+    }
+
+    /// Unknown codes per dataset, for the run summary
+    pub fn misses(&self) -> BTreeMap<&'static str, BTreeMap<String, usize>> {
+        self.misses.lock().map(|m| m.clone()).unwrap_or_default()
+    }
+
+    fn carrier_name(&self, code: &str) -> String {
+        self.lookup("carriers", &self.carriers, code)
+            .map(|c| c.name.clone())
+            .unwrap_or_default()
+    }
+
+    fn haul_type(&self, origin: &Airport, dest: &Airport) -> Option<String> {
+        let km = great_circle_km(origin.latitude?, origin.longitude?, dest.latitude?, dest.longitude?);
+        let haul = if km <= self.haul.short_max_km {
+            "SHORT"
+        } else if km <= self.haul.medium_max_km {
+            "MEDIUM"
+        } else {
+            "LONG"
+        };
+        Some(haul.to_string())
+    }
+
+    // Empty codes are not looked up; disabled (empty) datasets do not count misses
+    fn lookup<'a, T>(&self, dataset: &'static str, table: &'a HashMap<String, T>, code: &str) -> Option<&'a T> {
+        if code.is_empty() || table.is_empty() {
+            return None;
+        }
+
+        let found = table.get(code);
+        if found.is_none()
+            && let Ok(mut misses) = self.misses.lock()
+        {
+            *misses.entry(dataset).or_default().entry(code.to_string()).or_default() += 1;
+        }
+        found
+    }
+}
+
+/// segment is the origin and destination airport codes concatenated
+pub fn split_segment(segment: &str) -> (&str, &str) {
+    let split = segment.char_indices().nth(3).map(|(i, _)| i).unwrap_or(segment.len());
+    segment.split_at(split)
+}
+
+async fn load_dataset<T: DeserializeOwned, S: Storage>(storage: &S, location: Option<&str>) -> Result<Vec<T>> {
+    let Some(location) = location else {
+        return Ok(Vec::new());
+    };
+
+    let data = crate::storage::read_location(storage, location)
+        .await
+        .with_context(|| format!("reading reference data from {}", location))?;
+
+    let mut reader = csv::Reader::from_reader(data.as_slice());
+    let mut rows = Vec::new();
+    for row in reader.deserialize() {
+        rows.push(row.with_context(|| format!("parsing reference data {}", location))?);
+    }
+    Ok(rows)
+}
+
+fn great_circle_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
@@ -0,0 +1,24 @@
+use anyhow::Result;
+use std::future::Future;
+
+/// Destination of parsed rows, such as the chunked CSV writer or the PostgreSQL sink.
+/// Rows of one source file are written between `begin_file` and `end_file`, or
+/// discarded with `abort_file` when the file fails; `finalize` flushes whatever is
+/// still buffered once the run is over.
+pub trait RecordSink<T: Sync> {
+    fn begin_file(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn write_record(&mut self, rec: &T) -> impl Future<Output = Result<()>> + Send;
+
+    fn end_file(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn abort_file(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    fn finalize(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
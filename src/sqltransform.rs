@@ -0,0 +1,109 @@
+use anyhow::{Context, Result, bail};
+use arrow::record_batch::RecordBatch;
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+use datafusion::datasource::MemTable;
+use datafusion::prelude::SessionContext;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::sync::{Arc, OnceLock};
+
+use crate::arrowbatch;
+use crate::models::Record;
+use crate::storage::{Storage, read_location};
+
+/// User-supplied SQL query run over the records of a file before they are written.
+/// The records are registered as one table with the arrowbatch schema; the query may
+/// filter, derive columns or aggregate, and its result replaces the records. Every
+/// `apply` is a separate query, so an aggregation covers only the records passed in.
+pub struct SqlTransform {
+    sql: String,
+    table_name: String,
+    batch_size: usize,
+    // result column names; serde wants them static, so they are leaked once per transform
+    columns: OnceLock<Arc<[&'static str]>>,
+}
+
+impl SqlTransform {
+    pub fn new(sql: &str, table_name: &str, batch_size: usize) -> Self {
+        Self {
+            sql: sql.trim().trim_end_matches(';').to_string(),
+            table_name: table_name.to_string(),
+            batch_size,
+            columns: OnceLock::new(),
+        }
+    }
+
+    /// Reads the query from a local path or an s3:// location
+    pub async fn load<S: Storage>(storage: &S, location: &str, table_name: &str, batch_size: usize) -> Result<Self> {
+        let data = read_location(storage, location)
+            .await
+            .with_context(|| format!("loading SQL transform {}", location))?;
+        let sql = String::from_utf8(data).with_context(|| format!("SQL transform {} is not UTF-8", location))?;
+        Ok(Self::new(&sql, table_name, batch_size))
+    }
+
+    /// Runs the query over `records` and returns its result
+    pub async fn apply(&self, records: &[Record]) -> Result<Vec<RecordBatch>> {
+        let batches = arrowbatch::record_batches(records, self.batch_size).collect::<Result<Vec<_>>>()?;
+        let table = MemTable::try_new(arrowbatch::record_schema(), vec![batches])?;
+
+        let ctx = SessionContext::new();
+        ctx.register_table(self.table_name.as_str(), Arc::new(table))?;
+        let frame = ctx.sql(&self.sql).await.context("planning SQL transform")?;
+        frame.collect().await.context("running SQL transform")
+    }
+
+    /// Runs the query over `records` and returns its result as CSV-serializable rows
+    pub async fn apply_rows(&self, records: &[Record]) -> Result<Vec<SqlRow>> {
+        self.rows(&self.apply(records).await?)
+    }
+
+    /// Formats query result batches into rows
+    pub fn rows(&self, batches: &[RecordBatch]) -> Result<Vec<SqlRow>> {
+        let Some(first) = batches.first() else {
+            return Ok(Vec::new());
+        };
+        let schema = first.schema();
+        let names = schema.fields().iter().map(|f| f.name().as_str());
+        let columns = self
+            .columns
+            .get_or_init(|| names.clone().map(|n| &*Box::leak(n.to_string().into_boxed_str())).collect())
+            .clone();
+        // one query gives the same columns for every file
+        if !columns.iter().copied().eq(names) {
+            bail!("SQL transform result columns changed from {:?}", columns);
+        }
+
+        let options = FormatOptions::default().with_null("");
+        let mut rows = Vec::new();
+        for batch in batches {
+            let formatters = batch
+                .columns()
+                .iter()
+                .map(|array| ArrayFormatter::try_new(array.as_ref(), &options))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            for row in 0..batch.num_rows() {
+                let values = formatters.iter().map(|f| f.value(row).to_string()).collect();
+                rows.push(SqlRow { columns: columns.clone(), values });
+            }
+        }
+        Ok(rows)
+    }
+}
+
+/// One row of a query result with every value formatted as text, nulls as "".
+/// Serializes as a struct, so the CSV writers put the column names in the header.
+#[derive(Clone, Debug)]
+pub struct SqlRow {
+    columns: Arc<[&'static str]>,
+    values: Vec<String>,
+}
+
+impl Serialize for SqlRow {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut row = serializer.serialize_struct("SqlRow", self.columns.len())?;
+        for (column, value) in self.columns.iter().zip(&self.values) {
+            row.serialize_field(column, value)?;
+        }
+        row.end()
+    }
+}
@@ -0,0 +1,127 @@
+use aws_config::BehaviorVersion;
+use aws_sdk_sqs::Client;
+use aws_sdk_sqs::types::Message;
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::storage::ObjectInfo;
+
+pub async fn make_sqs_client(endpoint_url: Option<&str>) -> Client {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    // local stand-ins such as ElasticMQ expose the SQS API on a custom endpoint
+    if let Some(url) = endpoint_url {
+        loader = loader.endpoint_url(url);
+    }
+    let config = loader.load().await;
+    Client::new(&config)
+}
+
+pub async fn receive_messages(
+    client: &Client,
+    queue_url: &str,
+    max_messages: i32,
+    wait_time_seconds: i32,
+) -> Result<Vec<Message>> {
+    let resp = client
+        .receive_message()
+        .queue_url(queue_url)
+        .max_number_of_messages(max_messages)
+        .wait_time_seconds(wait_time_seconds)
+        .send()
+        .await?;
+
+    Ok(resp.messages.unwrap_or_default())
+}
+
+pub async fn delete_message(client: &Client, queue_url: &str, receipt_handle: &str) -> Result<()> {
+    client
+        .delete_message()
+        .queue_url(queue_url)
+        .receipt_handle(receipt_handle)
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+// Minimal shape of an S3 event notification, only the fields we use
+#[derive(Debug, Deserialize)]
+struct S3Event {
+    #[serde(rename = "Records", default)]
+    records: Vec<S3EventRecord>,
+}
+
+#[derive(Debug, Deserialize)]
+struct S3EventRecord {
+    #[serde(rename = "eventName", default)]
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Debug, Deserialize)]
+struct S3Entity {
+    bucket: S3Bucket,
+    object: S3Object,
+}
+
+#[derive(Debug, Deserialize)]
+struct S3Bucket {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct S3Object {
+    key: String,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(rename = "eTag", default)]
+    e_tag: Option<String>,
+}
+
+/// Returns the bucket and object (key, size, ETag) of the ObjectCreated records in a message body.
+/// Bodies without records (e.g. the s3:TestEvent sent on setup) yield nothing.
+pub fn created_objects(body: &str) -> Result<Vec<(String, ObjectInfo)>> {
+    let event: S3Event = serde_json::from_str(body)?;
+
+    let objects = event
+        .records
+        .into_iter()
+        .filter(|r| r.event_name.starts_with("ObjectCreated"))
+        .map(|r| {
+            let object = ObjectInfo {
+                key: decode_key(&r.s3.object.key),
+                size: r.s3.object.size,
+                // notifications carry the ETag without the quotes S3 puts around it elsewhere
+                etag: r.s3.object.e_tag.map(|etag| format!("\"{}\"", etag.trim_matches('"'))),
+            };
+            (r.s3.bucket.name, object)
+        })
+        .collect();
+
+    Ok(objects)
+}
+
+// S3 URL-encodes object keys in notifications, with '+' standing for a space
+fn decode_key(key: &str) -> String {
+    let bytes = key.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
@@ -0,0 +1,304 @@
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// An object as a listing reports it. Size and ETag, where the store has them, let a
+/// download be checked against the listing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: Option<u64>,
+    pub etag: Option<String>,
+}
+
+/// Object store the pipeline reads feed files from and writes its outputs to.
+/// Objects are addressed by bucket and key, as in S3.
+pub trait Storage: Send + Sync {
+    /// Keys under `prefix`, in the order the store returns them
+    fn list(&self, bucket: &str, prefix: &str) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    /// Objects under `prefix` with their size and ETag where the store reports them
+    fn list_objects(&self, bucket: &str, prefix: &str) -> impl Future<Output = Result<Vec<ObjectInfo>>> + Send {
+        async move {
+            let keys = self.list(bucket, prefix).await?;
+            Ok(keys.into_iter().map(|key| ObjectInfo { key, ..Default::default() }).collect())
+        }
+    }
+
+    /// Whole content of an object
+    fn get(&self, bucket: &str, key: &str) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Whole content of a listed object; fails if it no longer matches the listing,
+    /// e.g. a truncated download or an object replaced since it was listed
+    fn get_verified(&self, bucket: &str, object: &ObjectInfo) -> impl Future<Output = Result<Vec<u8>>> + Send {
+        async move {
+            let data = self.get(bucket, &object.key).await?;
+            check_length(object, data.len())?;
+            Ok(data)
+        }
+    }
+
+    /// Creates or replaces an object
+    fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Creates or replaces an object and attaches `metadata` (e.g. row count, source
+    /// keys) where the store supports it; stores without object metadata ignore it
+    fn put_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        metadata: &BTreeMap<String, String>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = metadata;
+        self.put(bucket, key, data)
+    }
+
+    /// As put_with_metadata, and sends `checksum` along for stores that verify what
+    /// they receive; the others ignore it
+    fn put_checked(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        metadata: &BTreeMap<String, String>,
+        checksum: &Checksum,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let _ = checksum;
+        self.put_with_metadata(bucket, key, data, metadata)
+    }
+}
+
+/// Fails unless `len` downloaded bytes are what the listing reported for `object`
+pub fn check_length(object: &ObjectInfo, len: usize) -> Result<()> {
+    match object.size {
+        Some(size) if (len as u64) < size => {
+            bail!("refusing truncated object {}: got {} of {} bytes", object.key, len, size)
+        }
+        Some(size) if len as u64 != size => {
+            bail!("refusing object {}: got {} bytes but the listing reports {}", object.key, len, size)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checksum sent with every uploaded output and recorded in the run manifest
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChecksumAlgorithm {
+    /// sent to S3 as Content-MD5
+    Md5,
+    Crc32c,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn compute(self, data: &[u8]) -> Checksum {
+        let digest = match self {
+            ChecksumAlgorithm::Md5 => Md5::digest(data).to_vec(),
+            ChecksumAlgorithm::Crc32c => crc32c::crc32c(data).to_be_bytes().to_vec(),
+            ChecksumAlgorithm::Sha256 => Sha256::digest(data).to_vec(),
+        };
+        Checksum { algorithm: self, value: BASE64.encode(digest) }
+    }
+}
+
+/// A checksum in the base64 form S3 takes and reports
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub value: String,
+}
+
+/// An object written through ChecksumStorage
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadedObject {
+    pub key: String,
+    pub bytes: u64,
+    pub checksum: Checksum,
+}
+
+/// Storage wrapper that checksums every object it writes, sends the checksum with the
+/// upload and keeps a list of the uploads for the run manifest. Clones share the list.
+#[derive(Clone, Debug)]
+pub struct ChecksumStorage<S: Storage> {
+    inner: S,
+    algorithm: ChecksumAlgorithm,
+    uploads: Arc<Mutex<Vec<UploadedObject>>>,
+}
+
+impl<S: Storage> ChecksumStorage<S> {
+    pub fn new(inner: S, algorithm: ChecksumAlgorithm) -> Self {
+        Self { inner, algorithm, uploads: Arc::default() }
+    }
+
+    /// The objects written so far, by key
+    pub fn uploads(&self) -> Vec<UploadedObject> {
+        let mut uploads = self.uploads.lock().map(|u| u.clone()).unwrap_or_default();
+        uploads.sort_by(|a, b| a.key.cmp(&b.key));
+        uploads
+    }
+}
+
+impl<S: Storage> Storage for ChecksumStorage<S> {
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(bucket, prefix).await
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        self.inner.list_objects(bucket, prefix).await
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        self.inner.get(bucket, key).await
+    }
+
+    async fn get_verified(&self, bucket: &str, object: &ObjectInfo) -> Result<Vec<u8>> {
+        self.inner.get_verified(bucket, object).await
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        self.put_with_metadata(bucket, key, data, &BTreeMap::new()).await
+    }
+
+    async fn put_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        metadata: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let checksum = self.algorithm.compute(&data);
+        self.put_checked(bucket, key, data, metadata, &checksum).await
+    }
+
+    async fn put_checked(
+        &self,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        metadata: &BTreeMap<String, String>,
+        checksum: &Checksum,
+    ) -> Result<()> {
+        let bytes = data.len() as u64;
+        self.inner.put_checked(bucket, key, data, metadata, checksum).await?;
+        if let Ok(mut uploads) = self.uploads.lock() {
+            uploads.push(UploadedObject { key: key.to_string(), bytes, checksum: checksum.clone() });
+        }
+        Ok(())
+    }
+}
+
+// Longest source key list kept whole in object metadata; S3 allows 2 KB of user metadata
+const SOURCE_KEYS_MAX_LEN: usize = 1024;
+
+/// The keys an output object was built from, comma-separated, as an object metadata
+/// value. Long lists are shortened to the first and last key and their count.
+pub fn source_keys_metadata(keys: &[String]) -> String {
+    let joined = keys.join(",");
+    match (keys.first(), keys.last()) {
+        (Some(first), Some(last)) if joined.len() > SOURCE_KEYS_MAX_LEN => {
+            format!("{},...,{} ({} keys)", first, last, keys.len())
+        }
+        _ => joined,
+    }
+}
+
+/// Storage on the local file system: object `key` of `bucket` is the file
+/// `<root>/<bucket>/<key>`
+#[derive(Clone, Debug)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, bucket: &str, key: &str) -> PathBuf {
+        self.root.join(bucket).join(key)
+    }
+}
+
+impl Storage for LocalStorage {
+    async fn list(&self, bucket: &str, prefix: &str) -> Result<Vec<String>> {
+        let bucket_dir = self.root.join(bucket);
+        let mut keys = Vec::new();
+        let mut dirs = vec![bucket_dir.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(path);
+                } else if let Some(key) = object_key(&bucket_dir, &path)
+                    && key.starts_with(prefix)
+                {
+                    keys.push(key);
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn list_objects(&self, bucket: &str, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut objects = Vec::new();
+        for key in self.list(bucket, prefix).await? {
+            let size = tokio::fs::metadata(self.path(bucket, &key)).await?.len();
+            objects.push(ObjectInfo { key, size: Some(size), etag: None });
+        }
+        Ok(objects)
+    }
+
+    async fn get(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
+        let path = self.path(bucket, key);
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("reading {}", path.display()))
+    }
+
+    async fn put(&self, bucket: &str, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(bucket, key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+// "/"-separated key of a file below the bucket directory
+fn object_key(bucket_dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(bucket_dir).ok()?;
+    let parts: Vec<&str> = relative.iter().map(|p| p.to_str()).collect::<Option<_>>()?;
+    Some(parts.join("/"))
+}
+
+/// Reads a whole file given either as "s3://bucket/key", resolved through `storage`,
+/// or as a local path
+pub async fn read_location<S: Storage>(storage: &S, location: &str) -> Result<Vec<u8>> {
+    if let Some(rest) = location.strip_prefix("s3://") {
+        let (bucket, key) = rest
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid S3 location {:?}", location))?;
+        return storage.get(bucket, key).await;
+    }
+
+    Ok(tokio::fs::read(location).await?)
+}
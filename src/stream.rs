@@ -0,0 +1,72 @@
+use anyhow::{Result, anyhow};
+use quick_xml::Reader;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+
+use crate::models::{ParsedFeed, Record};
+use crate::parser::{ParseOptions, parse_xml_each};
+
+// Records parsed ahead of the consumer before the parser thread blocks
+const CHANNEL_RECORDS: usize = 1024;
+
+/// Iterator over the records of one feed file, parsed on a background thread so that
+/// only a bounded number of records is held in memory. Yields the same records as
+/// parse_xml_with_options, in the same order; a parse failure is yielded once as
+/// the last item. Dropping the stream stops the parser.
+pub struct RecordStream {
+    records: Receiver<Record>,
+    parser: Option<JoinHandle<Result<ParsedFeed>>>,
+    summary: Option<ParsedFeed>,
+}
+
+impl RecordStream {
+    pub fn open(path: impl AsRef<Path>, options: ParseOptions) -> Result<Self> {
+        let file = File::open(path.as_ref())
+            .map_err(|e| anyhow!("opening {}: {}", path.as_ref().display(), e))?;
+        Ok(Self::new(BufReader::new(file), options))
+    }
+
+    pub fn new<R: BufRead + Send + 'static>(input: R, options: ParseOptions) -> Self {
+        let (sender, records) = mpsc::sync_channel(CHANNEL_RECORDS);
+
+        let parser = thread::spawn(move || {
+            let mut reader = Reader::from_reader(input);
+            reader.trim_text(true);
+            parse_xml_each(&mut reader, &options, |rec| {
+                sender.send(rec).map_err(|_| anyhow!("record stream dropped"))
+            })
+        });
+
+        Self { records, parser: Some(parser), summary: None }
+    }
+
+    /// Taxes, links and stats of the file once every record was yielded.
+    /// The returned feed has no records.
+    pub fn summary(&self) -> Option<&ParsedFeed> {
+        self.summary.as_ref()
+    }
+}
+
+impl Iterator for RecordStream {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(rec) = self.records.recv() {
+            return Some(Ok(rec));
+        }
+
+        // the sender is gone, the parser finished
+        let parser = self.parser.take()?;
+        match parser.join() {
+            Ok(Ok(feed)) => {
+                self.summary = Some(feed);
+                None
+            }
+            Ok(Err(e)) => Some(Err(e)),
+            Err(_) => Some(Err(anyhow!("parser thread panicked"))),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Result, bail};
+use csv::WriterBuilder;
+use std::collections::BTreeMap;
+
+use crate::config;
+use crate::models::Record;
+use crate::sink::RecordSink;
+use crate::storage::Storage;
+
+// Record count and one sum per measure of a group
+#[derive(Clone, Debug, Default)]
+struct Totals {
+    records: u64,
+    sums: Vec<f64>,
+}
+
+impl Totals {
+    fn add(&mut self, amounts: &[f64]) {
+        self.records += 1;
+        if self.sums.is_empty() {
+            self.sums = vec![0.0; amounts.len()];
+        }
+        for (sum, amount) in self.sums.iter_mut().zip(amounts) {
+            *sum += amount;
+        }
+    }
+}
+
+/// Sink that keeps running totals per day and dimension values instead of rows, and
+/// writes them as `<prefix>.csv` and `<prefix>.json` next to the CSV chunks on finalize.
+/// Memory grows with the number of distinct groups, not with the number of records.
+pub struct DailySummary<S: Storage> {
+    storage: S,
+    bucket: String,
+    key_prefix: String,
+    date_column: String,
+    dimensions: Vec<String>,
+    // (output name, Record column)
+    measures: Vec<(String, String)>,
+    groups: BTreeMap<Vec<String>, Totals>,
+    // amounts that were neither empty nor a number, counted as 0
+    unparsed: BTreeMap<String, u64>,
+}
+
+impl<S: Storage> DailySummary<S> {
+    pub fn new(
+        prefix: &str,
+        bucket: &str,
+        storage: S,
+        timestamp: &str,
+        date_column: &str,
+        dimensions: &[&str],
+        measures: &[(&str, &str)],
+    ) -> Result<Self> {
+        let probe = Record::default();
+        let columns = std::iter::once(date_column)
+            .chain(dimensions.iter().copied())
+            .chain(measures.iter().map(|(_, column)| *column));
+        for column in columns {
+            if probe.field(column).is_none() {
+                bail!("summary column {:?} is not a Record column", column);
+            }
+        }
+
+        Ok(Self {
+            storage,
+            bucket: bucket.to_string(),
+            key_prefix: format!("{}/{}/{}", config::FOLDER_NAME, timestamp, prefix),
+            date_column: date_column.to_string(),
+            dimensions: dimensions.iter().map(|d| d.to_string()).collect(),
+            measures: measures.iter().map(|(name, column)| (name.to_string(), column.to_string())).collect(),
+            groups: BTreeMap::new(),
+            unparsed: BTreeMap::new(),
+        })
+    }
+
+    fn csv(&self) -> Result<Vec<u8>> {
+        let mut writer = WriterBuilder::new().from_writer(Vec::new());
+
+        let mut header = vec!["date".to_string()];
+        header.extend(self.dimensions.iter().cloned());
+        header.push("record_count".to_string());
+        header.extend(self.measures.iter().map(|(name, _)| format!("{}_total", name)));
+        writer.write_record(&header)?;
+
+        for (key, totals) in &self.groups {
+            let mut row = key.clone();
+            row.push(totals.records.to_string());
+            row.extend(totals.sums.iter().map(|sum| format_amount(*sum)));
+            writer.write_record(&row)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+
+    fn json(&self) -> Result<Vec<u8>> {
+        let mut overall = Totals::default();
+        let groups: Vec<serde_json::Value> = self
+            .groups
+            .iter()
+            .map(|(key, totals)| {
+                overall.records += totals.records;
+                overall.sums.resize(totals.sums.len(), 0.0);
+                for (sum, group_sum) in overall.sums.iter_mut().zip(&totals.sums) {
+                    *sum += group_sum;
+                }
+
+                let mut group = serde_json::Map::new();
+                group.insert("date".to_string(), key[0].clone().into());
+                for (dimension, value) in self.dimensions.iter().zip(&key[1..]) {
+                    group.insert(dimension.clone(), value.clone().into());
+                }
+                group.insert("record_count".to_string(), totals.records.into());
+                for ((name, _), sum) in self.measures.iter().zip(&totals.sums) {
+                    group.insert(format!("{}_total", name), round_amount(*sum).into());
+                }
+                serde_json::Value::Object(group)
+            })
+            .collect();
+
+        // run-wide figures for reconciliation against the row-level output
+        let mut totals = serde_json::Map::new();
+        totals.insert("record_count".to_string(), overall.records.into());
+        for (i, (name, _)) in self.measures.iter().enumerate() {
+            let sum = overall.sums.get(i).copied().unwrap_or_default();
+            totals.insert(format!("{}_total", name), round_amount(sum).into());
+        }
+
+        let summary = serde_json::json!({
+            "date_column": self.date_column,
+            "groups": groups,
+            "totals": totals,
+            "unparsed_amounts": self.unparsed,
+        });
+        Ok(serde_json::to_vec_pretty(&summary)?)
+    }
+}
+
+impl<S: Storage> RecordSink<Record> for DailySummary<S> {
+    async fn write_record(&mut self, rec: &Record) -> Result<()> {
+        let date = rec.field(&self.date_column).unwrap_or_default();
+        let mut key = vec![day(date).to_string()];
+        key.extend(self.dimensions.iter().map(|d| rec.field(d).unwrap_or_default().to_string()));
+
+        let mut amounts = Vec::with_capacity(self.measures.len());
+        for (_, column) in &self.measures {
+            let value = rec.field(column).unwrap_or_default().trim();
+            let amount = match value {
+                "" => 0.0,
+                v => v.parse().unwrap_or_else(|_| {
+                    *self.unparsed.entry(column.clone()).or_default() += 1;
+                    0.0
+                }),
+            };
+            amounts.push(amount);
+        }
+
+        self.groups.entry(key).or_default().add(&amounts);
+        Ok(())
+    }
+
+    async fn finalize(&mut self) -> Result<()> {
+        let csv_key = format!("{}.csv", self.key_prefix);
+        let metadata = BTreeMap::from([("row-count".to_string(), self.groups.len().to_string())]);
+        self.storage.put_with_metadata(&self.bucket, &csv_key, self.csv()?, &metadata).await?;
+
+        let json_key = format!("{}.json", self.key_prefix);
+        self.storage.put(&self.bucket, &json_key, self.json()?).await?;
+        Ok(())
+    }
+}
+
+// Day part of a date or date-time value such as 2025-11-25T08:15:00Z
+fn day(value: &str) -> &str {
+    value.split(['T', ' ']).next().unwrap_or(value)
+}
+
+// Sums of decimal amounts carry binary noise, rounded to well below a minor unit
+fn round_amount(sum: f64) -> f64 {
+    (sum * 1e6).round() / 1e6
+}
+
+fn format_amount(sum: f64) -> String {
+    round_amount(sum).to_string()
+}
@@ -0,0 +1,129 @@
+use anyhow::{Context, Result, anyhow};
+use libxml::parser::{Parser, ParserOptions};
+use libxml::schemas::{SchemaParserContext, SchemaValidationContext};
+use quick_xml::Reader;
+use quick_xml::events::Event;
+use std::fmt;
+
+use crate::storage::Storage;
+
+/// One way a document does not conform to the schema
+#[derive(Clone, Debug)]
+pub struct Violation {
+    pub line: Option<i32>,
+    pub column: Option<i32>,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(col)) => write!(f, "line {}, column {}: {}", line, col, self.message),
+            (Some(line), None) => write!(f, "line {}: {}", line, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Validates XML documents against the feed XSD. libxml2 contexts are not Send, so
+/// they are built for each document from the schema text.
+pub struct XsdValidator {
+    xsd: Vec<u8>,
+}
+
+impl XsdValidator {
+    pub async fn load<S: Storage>(storage: &S, location: &str) -> Result<Self> {
+        let xsd = crate::storage::read_location(storage, location)
+            .await
+            .with_context(|| format!("reading XSD from {}", location))?;
+
+        let validator = Self { xsd };
+        // fail at start-up rather than on the first file if the schema itself is broken
+        validator.context()?;
+        Ok(validator)
+    }
+
+    /// Returns the violations of one document, empty when it conforms
+    pub fn validate(&self, xml: &[u8]) -> Result<Vec<Violation>> {
+        let mut context = self.context()?;
+
+        // libxml2 only reports that parsing failed, quick-xml tells where
+        if let Some(violation) = well_formedness_error(xml) {
+            return Ok(vec![violation]);
+        }
+        let options = ParserOptions {
+            recover: false,
+            no_net: true,
+            ..ParserOptions::default()
+        };
+        let doc = match Parser::default().parse_string_with_options(xml, options) {
+            Ok(doc) => doc,
+            Err(_) => {
+                // e.g. unclosed elements at the end of the file
+                return Ok(vec![Violation {
+                    line: None,
+                    column: None,
+                    message: "not well-formed".to_string(),
+                }]);
+            }
+        };
+
+        match context.validate_document(&doc) {
+            Ok(()) => Ok(Vec::new()),
+            Err(errors) => Ok(errors
+                .into_iter()
+                .map(|e| Violation {
+                    line: e.line.filter(|l| *l > 0),
+                    column: e.col.filter(|c| *c > 0),
+                    message: e.message.as_deref().unwrap_or_default().trim().to_string(),
+                })
+                .collect()),
+        }
+    }
+
+    fn context(&self) -> Result<SchemaValidationContext> {
+        let mut parser = SchemaParserContext::from_buffer(&self.xsd);
+        SchemaValidationContext::from_parser(&mut parser).map_err(|errors| {
+            let messages: Vec<String> = errors.iter().map(|e| e.message.as_deref().unwrap_or_default().trim().to_string()).collect();
+            anyhow!("invalid XSD: {}", messages.join("; "))
+        })
+    }
+}
+
+fn well_formedness_error(xml: &[u8]) -> Option<Violation> {
+    let mut reader = Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf) {
+            Ok(Event::Eof) => return None,
+            Ok(_) => buf.clear(),
+            Err(e) => {
+                let (line, column) = crate::diagnostics::line_col(xml, reader.buffer_position());
+                return Some(Violation {
+                    line: Some(line as i32),
+                    column: Some(column as i32),
+                    message: format!("not well-formed: {}", e),
+                });
+            }
+        }
+    }
+}
+
+/// Copies a non-conforming input under the quarantine prefix, with a report of its violations
+pub async fn quarantine<S: Storage>(
+    storage: &S,
+    bucket: &str,
+    key: &str,
+    quarantine_prefix: &str,
+    data: Vec<u8>,
+    violations: &[Violation],
+) -> Result<()> {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let target = format!("{}{}", quarantine_prefix, name);
+
+    let report: String = violations.iter().map(|v| format!("{}\n", v)).collect();
+
+    storage.put(bucket, &target, data).await?;
+    storage.put(bucket, &format!("{}.violations.txt", target), report.into_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,29 @@
+// Arrow batches built from records.
+
+use xmlpoc::Record;
+use xmlpoc::arrowbatch::RecordBatches;
+
+fn record(revenue: &str) -> anyhow::Result<Record> {
+    Ok(Record { revenue: revenue.to_string(), ..Default::default() })
+}
+
+#[test]
+fn a_batch_that_fails_to_convert_ends_the_iteration() {
+    let records = vec![record("1.5"), record("2"), record("3"), record("not a number"), record("5"), record("6")];
+    let mut batches = RecordBatches::new(records.into_iter(), 2);
+
+    assert_eq!(batches.next().unwrap().unwrap().num_rows(), 2);
+    let error = batches.next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("column revenue row 1"), "{}", error);
+    // the rows after the failed batch are not handed out as if nothing was lost
+    assert!(batches.next().is_none());
+}
+
+#[test]
+fn an_input_error_ends_the_iteration() {
+    let records = vec![record("1"), Err(anyhow::anyhow!("truncated feed")), record("3")];
+    let mut batches = RecordBatches::new(records.into_iter(), 2);
+
+    assert_eq!(batches.next().unwrap().unwrap_err().to_string(), "truncated feed");
+    assert!(batches.next().is_none());
+}
@@ -0,0 +1,54 @@
+// Date-time normalization of the issue, departure and arrival columns.
+
+use xmlpoc::Record;
+use xmlpoc::config;
+use xmlpoc::datetimes::{DateNormalizer, parse_date};
+
+fn normalizer(convert_to_utc: bool) -> DateNormalizer {
+    DateNormalizer::new(config::DATE_INPUT_FORMATS, config::DATETIME_OUTPUT_FORMAT, config::DATE_OUTPUT_FORMAT, convert_to_utc)
+}
+
+#[test]
+fn offsets_are_kept_and_give_the_flight_duration() {
+    let mut rec = Record {
+        issue_date: "201125".to_string(),
+        dep_date_time: "2025-12-01T10:30:00+01:00".to_string(),
+        arr_date_time: "2025-12-01T13:15:00-05:00".to_string(),
+        ..Default::default()
+    };
+
+    normalizer(false).normalize(&mut rec, None);
+    assert_eq!(rec.issue_date, "2025-11-20");
+    assert_eq!(rec.dep_date_time, "2025-12-01T10:30:00+01:00");
+    assert_eq!(rec.arr_date_time, "2025-12-01T13:15:00-05:00");
+    assert_eq!(rec.flight_duration_minutes, "525");
+    assert_eq!(rec.advance_purchase_days, "11");
+    // UTC columns only when asked for
+    assert_eq!(rec.dep_date_time_utc, "");
+
+    normalizer(true).normalize(&mut rec, None);
+    assert_eq!(rec.dep_date_time_utc, "2025-12-01T09:30:00");
+    assert_eq!(rec.arr_date_time_utc, "2025-12-01T18:15:00");
+}
+
+#[test]
+fn local_times_without_timezone_have_no_duration() {
+    let mut rec = Record {
+        dep_date_time: "2025-12-01T10:30:00".to_string(),
+        arr_date_time: "2025-12-01T13:15:00".to_string(),
+        ..Default::default()
+    };
+
+    normalizer(true).normalize(&mut rec, None);
+    assert_eq!(rec.dep_date_time, "2025-12-01T10:30:00");
+    assert_eq!(rec.flight_duration_minutes, "");
+}
+
+#[test]
+fn dates_parse_with_any_input_format() {
+    let expected = chrono::NaiveDate::from_ymd_opt(2025, 11, 20);
+    assert_eq!(parse_date("2025-11-20", config::DATE_INPUT_FORMATS), expected);
+    assert_eq!(parse_date("201125", config::DATE_INPUT_FORMATS), expected);
+    assert_eq!(parse_date("2025-11-20T23:30:00-02:00", config::DATE_INPUT_FORMATS), expected);
+    assert_eq!(parse_date("20/11/2025", config::DATE_INPUT_FORMATS), None);
+}
@@ -0,0 +1,154 @@
+// Deduplication of records across files and runs.
+
+use quick_xml::Reader;
+use xmlpoc::config;
+use xmlpoc::dedupe::{DedupePolicy, DedupeStage, DedupeState};
+use xmlpoc::models::TransactionRows;
+use xmlpoc::normalized::{SurrogateKeys, parse_normalized};
+use xmlpoc::{LocalStorage, ParseOptions, Record, parse_xml};
+
+fn stage(spill_parent: &std::path::Path, max_in_memory: usize) -> DedupeStage {
+    let parent = spill_parent.to_str().unwrap();
+    DedupeStage::new(&["ticket_no", "coupon_no"], DedupePolicy::LatestTimestamp, config::DATE_INPUT_FORMATS, max_in_memory, Some(parent))
+        .unwrap()
+}
+
+fn record(ticket_no: &str, timestamp: &str) -> Record {
+    Record {
+        ticket_no: ticket_no.to_string(),
+        coupon_no: "1".to_string(),
+        transaction_timestamp: timestamp.to_string(),
+        ..Default::default()
+    }
+}
+
+fn temp_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("xmlpoc_dedupe_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Timestamps of the surviving records
+fn kept(stage: DedupeStage) -> (Vec<String>, DedupeState) {
+    let mut deduped = stage.finish().unwrap();
+    let mut timestamps = Vec::new();
+    while let Some(rec) = deduped.next_record().unwrap() {
+        timestamps.push(rec.transaction_timestamp);
+    }
+    (timestamps, deduped.into_state())
+}
+
+#[test]
+fn timestamps_compare_as_instants() {
+    let dir = temp_dir("instants");
+    let mut stage = stage(&dir, 10);
+    // the later instant sorts first as text
+    stage.push(record("1", "2025-11-20T09:00:00Z")).unwrap();
+    stage.push(record("1", "2025-11-20T10:00:00+02:00")).unwrap();
+
+    assert_eq!(kept(stage).0, ["2025-11-20T09:00:00Z"]);
+}
+
+#[test]
+fn concurrent_stages_spill_to_their_own_directories() {
+    let dir = temp_dir("spill");
+    let mut first = stage(&dir, 1);
+    let mut second = stage(&dir, 1);
+    for (ticket_no, timestamp) in [("1", "2025-11-20T08:00:00"), ("1", "2025-11-20T09:00:00"), ("2", "2025-11-20T08:00:00")] {
+        first.push(record(ticket_no, timestamp)).unwrap();
+        second.push(record(ticket_no, "2025-11-21T08:00:00")).unwrap();
+    }
+
+    assert_eq!(kept(first).0, ["2025-11-20T09:00:00", "2025-11-20T08:00:00"]);
+    assert_eq!(kept(second).0, ["2025-11-21T08:00:00", "2025-11-21T08:00:00"]);
+    // spill directories go with the stages
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn later_runs_only_write_keys_that_outrank_the_saved_state() {
+    let dir = temp_dir("state");
+    let storage = LocalStorage::new(&dir);
+
+    let mut first_run = stage(&dir, 10);
+    first_run.push(record("1", "2025-11-20T08:00:00")).unwrap();
+    first_run.push(record("2", "2025-11-20T08:00:00")).unwrap();
+    let (written, state) = kept(first_run);
+    assert_eq!(written.len(), 2);
+    state.save(&storage, "output", "state/keys.jsonl").await.unwrap();
+
+    let state = DedupeState::load(&storage, "output", "state/keys.jsonl").await.unwrap();
+    assert_eq!(state.len(), 2);
+    let mut rerun = stage(&dir, 10).with_state(state);
+    rerun.push(record("1", "2025-11-20T08:00:00")).unwrap();
+    rerun.push(record("2", "2025-11-20T09:00:00")).unwrap();
+    let (written, state) = kept(rerun);
+    assert_eq!(written, ["2025-11-20T09:00:00"]);
+    assert_eq!(state.len(), 2);
+
+    // no saved state yet
+    assert!(DedupeState::load(&storage, "output", "missing.jsonl").await.unwrap().is_empty());
+}
+
+#[test]
+fn expired_keys_are_written_again() {
+    let dir = temp_dir("expire");
+
+    let mut first_run = stage(&dir, 10);
+    first_run.push(record("1", "2025-10-01T08:00:00")).unwrap();
+    first_run.push(record("2", "2025-11-20T08:00:00")).unwrap();
+    first_run.push(record("3", "not a timestamp")).unwrap();
+    let (_, mut state) = kept(first_run);
+    assert_eq!(state.len(), 3);
+
+    // keys before the cutoff and keys without a readable timestamp go
+    let cutoff = chrono::NaiveDate::from_ymd_opt(2025, 11, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+    assert_eq!(state.expire_before(cutoff), 2);
+    assert_eq!(state.len(), 1);
+
+    let mut rerun = stage(&dir, 10).with_state(state);
+    rerun.push(record("1", "2025-10-01T08:00:00")).unwrap();
+    rerun.push(record("2", "2025-11-20T08:00:00")).unwrap();
+    assert_eq!(kept(rerun).0, ["2025-10-01T08:00:00"]);
+}
+
+#[test]
+fn side_rows_are_written_only_with_the_surviving_record() {
+    let bytes = std::fs::read("tests/fixtures/sample.xml").unwrap();
+    let mut reader = Reader::from_reader(bytes.as_slice());
+    reader.trim_text(true);
+    let mut feed = parse_xml(&mut reader).unwrap();
+    let mut reader = Reader::from_reader(bytes.as_slice());
+    reader.trim_text(true);
+    let normalized = parse_normalized(&mut reader, "sample.xml", &mut SurrogateKeys::default(), &ParseOptions::default()).unwrap();
+
+    // one group of rows per record: the issued ticket has three taxes and two coupons
+    let feed_rows = feed.take_transaction_rows();
+    let normalized = normalized.into_transactions();
+    assert_eq!(feed_rows.iter().map(|(taxes, _)| taxes.len()).collect::<Vec<_>>(), [3, 0]);
+    assert_eq!(normalized.iter().map(|n| n.coupons.len()).collect::<Vec<_>>(), [2, 1]);
+    assert_eq!(normalized[1].documents[0].primary_ticket_no, feed.records[1].primary_ticket_no);
+
+    // the same transaction sent twice, the older copy loses with its rows; a stage of
+    // one record spills every push, so the rows also go through the spill files
+    let dir = temp_dir("rows");
+    let mut stage = stage(&dir, 1);
+    let (taxes, links) = feed_rows[0].clone();
+    for (timestamp, amount) in [("2025-11-20T09:00:00", "new"), ("2025-11-20T08:00:00", "old")] {
+        let mut rows = TransactionRows { taxes: taxes.clone(), links: links.clone(), normalized: normalized[0].clone() };
+        rows.taxes.iter_mut().for_each(|t| t.amount_accounting_currency = amount.to_string());
+        rows.normalized.documents.iter_mut().for_each(|d| d.currency = amount.to_string());
+        stage.push_transaction(record("1", timestamp), rows).unwrap();
+    }
+
+    let mut deduped = stage.finish().unwrap();
+    let (rec, rows) = deduped.next_transaction().unwrap().unwrap();
+    assert_eq!(rec.transaction_timestamp, "2025-11-20T09:00:00");
+    assert_eq!(rows.taxes.len(), 3);
+    assert!(rows.taxes.iter().all(|t| t.amount_accounting_currency == "new"));
+    assert_eq!(rows.normalized.coupons.len(), 2);
+    assert!(rows.normalized.documents.iter().all(|d| d.currency == "new"));
+    assert!(deduped.next_transaction().unwrap().is_none());
+    assert_eq!(deduped.removed(), 1);
+}
@@ -0,0 +1,12 @@
+// Parse error details shown in the logs.
+
+use xmlpoc::diagnostics::redact_snippet;
+
+#[test]
+fn redacted_snippets_keep_the_markup_and_mask_the_values() {
+    let snippet = r#"7890"/> <Document DocumentNbr="2201234567890" Name='DOE/JOHN'>MR DOE<!-- pax --></Doc"#;
+    let redacted = redact_snippet(snippet);
+    assert_eq!(redacted, r#"******* <Document DocumentNbr="*************" Name='********'>** ***<*** *** **></Doc"#);
+    // one char per char, so the caret of the error output stays in place
+    assert_eq!(redacted.chars().count(), snippet.chars().count());
+}
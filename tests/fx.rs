@@ -0,0 +1,61 @@
+// Reporting currency conversion from a daily rate file.
+
+use xmlpoc::config;
+use xmlpoc::fx::{FxConverter, FxRateSource};
+use xmlpoc::{LocalStorage, Record};
+
+async fn converter(name: &str, rates: &str) -> FxConverter {
+    let dir = std::env::temp_dir().join(format!("xmlpoc_fx_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("rates.csv");
+    std::fs::write(&path, rates).unwrap();
+
+    // leaked so the source can borrow it like a config constant
+    let location: &'static str = Box::leak(path.to_string_lossy().into_owned().into_boxed_str());
+    FxConverter::load(&LocalStorage::new(&dir), "EUR", FxRateSource::RateFile(location), 3, None, config::DATE_INPUT_FORMATS)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn accounted_amounts_convert_from_the_accounting_currency() {
+    let fx = converter("accounting", "date,from_currency,to_currency,rate\n2025-11-20,USD,EUR,0.5\n2025-11-20,GBP,EUR,1.2\n").await;
+    let mut rec = Record {
+        currency: "GBP".to_string(),
+        accounting_currency: "USD".to_string(),
+        issue_date: "2025-11-20".to_string(),
+        cpn_far_fare_amount_accounting_currency: "100".to_string(),
+        ..Default::default()
+    };
+
+    fx.convert(&mut rec);
+    assert_eq!(rec.fx_rate, "0.5");
+    assert_eq!(rec.cpn_far_fare_amount_reporting_currency, "50");
+}
+
+#[tokio::test]
+async fn issue_dates_are_read_with_the_feed_input_formats() {
+    let fx = converter("dates", "date,from_currency,to_currency,rate\n2025-11-19,USD,EUR,0.5\n").await;
+    let mut rec = Record {
+        accounting_currency: "USD".to_string(),
+        issue_date: "201125".to_string(),
+        ..Default::default()
+    };
+
+    fx.convert(&mut rec);
+    assert_eq!(rec.fx_rate_missing, "N");
+    assert_eq!(rec.fx_rate, "0.5");
+}
+
+#[tokio::test]
+async fn records_without_accounting_currency_have_no_rate() {
+    let fx = converter("missing", "date,from_currency,to_currency,rate\n2025-11-20,EUR,EUR,1\n").await;
+    let mut rec = Record {
+        currency: "EUR".to_string(),
+        issue_date: "2025-11-20".to_string(),
+        ..Default::default()
+    };
+
+    fx.convert(&mut rec);
+    assert_eq!(rec.fx_rate_missing, "Y");
+}
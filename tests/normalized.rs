@@ -0,0 +1,57 @@
+// Normalized tables against the record output: same filters, same rows in parallel.
+
+use quick_xml::Reader;
+use xmlpoc::models::NormalizedFeed;
+use xmlpoc::normalized::{SurrogateKeys, parse_normalized};
+use xmlpoc::parallel::parse_normalized_parallel;
+use xmlpoc::parser::ValueFilter;
+use xmlpoc::ParseOptions;
+
+fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new("tests/fixtures").join(name)).unwrap()
+}
+
+fn sequential(bytes: &[u8], keys: &mut SurrogateKeys, options: &ParseOptions) -> NormalizedFeed {
+    let mut reader = Reader::from_reader(bytes);
+    reader.trim_text(true);
+    parse_normalized(&mut reader, "sample.xml", keys, options).unwrap()
+}
+
+#[test]
+fn filtered_transactions_leave_no_rows_and_no_id_gaps() {
+    let bytes = fixture("sample.xml");
+    let options = ParseOptions {
+        document_status: ValueFilter::new(&[], &["ISSUED"]),
+        ..Default::default()
+    };
+
+    let feed = sequential(&bytes, &mut SurrogateKeys::default(), &options);
+    let tickets: Vec<&str> = feed.documents.iter().map(|d| d.primary_ticket_no.as_str()).collect();
+    assert_eq!(tickets, ["7241111111111"]);
+    assert_eq!(feed.documents[0].document_id, 1);
+    assert_eq!(feed.coupons.len(), 1);
+    assert_eq!(feed.coupons[0].coupon_id, 1);
+    assert!(feed.taxes.iter().all(|t| t.ticket_no == "7241111111111"));
+    assert!(feed.fares.iter().all(|f| f.primary_ticket_no == "7241111111111"));
+}
+
+#[test]
+fn parallel_tables_match_the_sequential_pass() {
+    for name in ["sample.xml", "sample_links.xml"] {
+        let bytes = fixture(name);
+        let options = ParseOptions::default();
+
+        // ids continue from earlier files in both passes
+        let mut sequential_keys = SurrogateKeys::default();
+        sequential(&bytes, &mut sequential_keys, &options);
+        let mut parallel_keys = SurrogateKeys::default();
+        sequential(&bytes, &mut parallel_keys, &options);
+
+        let expected = sequential(&bytes, &mut sequential_keys, &options);
+        // one byte per slice cuts at every Transaction
+        let actual = parse_normalized_parallel(&bytes, "sample.xml", &mut parallel_keys, &options, 1).unwrap();
+
+        assert_eq!(format!("{:?}", actual), format!("{:?}", expected), "{}", name);
+        assert_eq!(format!("{:?}", parallel_keys), format!("{:?}", sequential_keys), "{}", name);
+    }
+}
@@ -0,0 +1,152 @@
+// Record output of the parser on the fixture feeds.
+
+use quick_xml::Reader;
+use quick_xml::events::BytesStart;
+use xmlpoc::config;
+use xmlpoc::parallel::parse_xml_parallel;
+use xmlpoc::profiles::{FeedPath, Strictness, tags};
+use xmlpoc::normalized::{SurrogateKeys, parse_normalized};
+use xmlpoc::parser::ValueFilter;
+use xmlpoc::{ParseOptions, ParsedFeed, parse_xml, parse_xml_with_options};
+
+fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(std::path::Path::new("tests/fixtures").join(name)).unwrap()
+}
+
+fn parse(bytes: &[u8]) -> anyhow::Result<ParsedFeed> {
+    let mut reader = Reader::from_reader(bytes);
+    reader.trim_text(true);
+    parse_xml(&mut reader)
+}
+
+#[test]
+fn unlisted_feed_versions_are_reported_not_rejected() {
+    let sample = String::from_utf8(fixture("sample.xml")).unwrap();
+    for root in [r#"<AMA_REV.Feed Version="2.0">"#, r#"<AMA_REV.Feed xmlns="http://xml.amadeus.com/2010/06/AMA_REV">"#] {
+        let bytes = sample.replacen(r#"<AMA_REV.Feed Version="1.0">"#, root, 1);
+        let feed = parse(bytes.as_bytes()).unwrap();
+
+        assert_eq!(feed.records.len(), 2, "{}", root);
+        assert!(feed.stats.warnings.iter().any(|w| w.starts_with("unsupported feed")), "{}", root);
+    }
+}
+
+#[test]
+fn tag_ids_are_fixed_for_known_tags_and_per_parser_for_others() {
+    let open = |path: &mut FeedPath, name: &str| path.open(&BytesStart::new(name)).unwrap();
+
+    let mut first = FeedPath::new(config::FEED_PROFILES, Strictness::Warn);
+    open(&mut first, "AMA_REV.Feed");
+    open(&mut first, "Transaction");
+    open(&mut first, "VendorExtension");
+    assert_eq!(first.segments()[..2], [tags::AMA_REV_FEED, tags::TRANSACTION]);
+    assert_eq!(first.names(), ["AMA_REV.Feed", "Transaction", "VendorExtension"]);
+
+    // another parser numbers the names it meets on its own
+    let mut second = FeedPath::new(config::FEED_PROFILES, Strictness::Warn);
+    open(&mut second, "AMA_REV.Feed");
+    open(&mut second, "OtherExtension");
+    assert_eq!(second.segments()[1], first.segments()[2]);
+    assert_eq!(second.name(second.segments()[1]), "OtherExtension");
+}
+
+// The Transaction elements of a fixture
+fn transactions(name: &str) -> String {
+    let feed = String::from_utf8(fixture(name)).unwrap();
+    let start = feed.find("<Transaction>").unwrap();
+    let end = feed.rfind("</Transaction>").unwrap() + "</Transaction>".len();
+    feed[start..end].to_string()
+}
+
+#[test]
+fn parallel_output_is_identical_to_the_sequential_parse() {
+    // the SELLING fare of sample.xml has no ROE, so the parser is still waiting for one
+    // when the next transaction brings a ROE for an amount type it does not capture
+    let unfinished = r#"<Transaction><Event><EntityStatus>ISSUED</EntityStatus></Event><Document>
+      <Fares><Fare FareDescription="NET"><AccountableEntity><Amount><AmountType>BASE</AmountType><Amount Amount="90.00"/><ROE>9.9</ROE></Amount></AccountableEntity></Fare></Fares>
+      <Coupon DocumentNbr="1" ConjunctiveDocumentNbr="1" Number="1" Status="O"/></Document></Transaction>"#;
+    let body = [transactions("sample.xml"), unfinished.to_string(), transactions("sample_links.xml"), transactions("sample.xml")].join("\n");
+    let feed = format!("<?xml version=\"1.0\"?>\n<AMA_REV.Feed Version=\"1.0\">\n{}\n</AMA_REV.Feed>\n", body);
+
+    let expected = parse(feed.as_bytes()).unwrap();
+    assert_eq!(expected.records.len(), 7);
+    // every slice size from one transaction per slice to the whole file in one
+    for chunk_bytes in [1, 500, 2_000, 1_000_000] {
+        let actual = parse_xml_parallel(feed.as_bytes(), &ParseOptions::default(), chunk_bytes).unwrap();
+        assert_eq!(format!("{:?}", actual.records), format!("{:?}", expected.records), "chunk {}", chunk_bytes);
+        assert_eq!(format!("{:?}", actual.taxes), format!("{:?}", expected.taxes), "chunk {}", chunk_bytes);
+        assert_eq!(format!("{:?}", actual.links), format!("{:?}", expected.links), "chunk {}", chunk_bytes);
+        assert_eq!(format!("{:?}", actual.stats), format!("{:?}", expected.stats), "chunk {}", chunk_bytes);
+    }
+}
+
+#[test]
+fn coupon_taxes_are_emitted_as_rows_that_add_up_to_the_record_sum() {
+    let feed = parse(&fixture("sample.xml")).unwrap();
+    let rows: Vec<(&str, &str, &str, &str, &str)> = feed
+        .taxes
+        .iter()
+        .map(|t| (t.coupon_no.as_str(), t.nature_code.as_str(), t.iso_code.as_str(), t.is_refundable.as_str(), t.amount_accounting_currency.as_str()))
+        .collect();
+    assert_eq!(rows, [("1", "AC", "YQ", "N", "20.00"), ("1", "GV", "YR", "Y", "7.50"), ("2", "AC", "YQ", "N", "15.00")]);
+    assert!(feed.taxes.iter().all(|t| t.primary_ticket_no == "2201234567890" && t.ticket_no == "2201234567890"));
+
+    let amount = |refundable: &str| -> f64 {
+        feed.taxes.iter().filter(|t| t.is_refundable == refundable).map(|t| t.amount_accounting_currency.parse::<f64>().unwrap()).sum()
+    };
+    assert_eq!(amount("N"), 35.0);
+    assert_eq!(amount("Y"), 7.5);
+
+    let issued = feed.records.iter().find(|r| r.ticket_no == "2201234567890").unwrap();
+    assert_eq!(issued.sum_cpn_txo_tax_amount_accounting_currency.parse::<f64>().unwrap(), amount("N") + amount("Y"));
+}
+
+#[test]
+fn record_tax_sum_covers_only_its_own_transaction() {
+    // the same issued ticket three times in one file: each record carries its own
+    // 42.50 of coupon taxes, not a total running on from the transactions before it
+    let body = [transactions("sample.xml"), transactions("sample.xml"), transactions("sample.xml")].join("\n");
+    let feed = format!("<?xml version=\"1.0\"?>\n<AMA_REV.Feed Version=\"1.0\">\n{}\n</AMA_REV.Feed>\n", body);
+    let feed = parse(feed.as_bytes()).unwrap();
+
+    let sums: Vec<(&str, &str)> = feed
+        .records
+        .iter()
+        .map(|r| (r.ticket_no.as_str(), r.sum_cpn_txo_tax_amount_accounting_currency.as_str()))
+        .collect();
+    let once = [("2201234567890", "42.5"), ("7241111111111", "")];
+    assert_eq!(sums, [once, once, once].concat());
+}
+
+#[test]
+fn coupon_status_filter_judges_a_transaction_by_its_last_coupon() {
+    let coupon = |number: &str, status: &str| {
+        format!(r#"<Coupon DocumentNbr="1" ConjunctiveDocumentNbr="1" Number="{}" Status="{}"></Coupon>"#, number, status)
+    };
+    let options = ParseOptions {
+        coupon_status: ValueFilter::new(&[], &["V"]),
+        ..Default::default()
+    };
+
+    for (coupons, kept) in [([("1", "O"), ("2", "V")], 0), ([("1", "V"), ("2", "O")], 1)] {
+        let coupons: String = coupons.iter().map(|(number, status)| coupon(number, status)).collect();
+        let feed = format!(
+            "<AMA_REV.Feed Version=\"1.0\"><Transaction><Event><EntityStatus>ISSUED</EntityStatus></Event><Document>{}</Document></Transaction></AMA_REV.Feed>",
+            coupons
+        );
+        let mut reader = Reader::from_reader(feed.as_bytes());
+        reader.trim_text(true);
+        let parsed = parse_xml_with_options(&mut reader, &options).unwrap();
+
+        assert_eq!(parsed.records.len(), kept, "{}", coupons);
+        // the earlier coupon goes with the transaction either way
+        assert!(parsed.records.iter().all(|r| r.coupon_no == "2" && r.coupon_status == "O"), "{}", coupons);
+
+        // the normalized tables keep or drop the same transactions
+        let mut reader = Reader::from_reader(feed.as_bytes());
+        reader.trim_text(true);
+        let normalized = parse_normalized(&mut reader, "mixed.xml", &mut SurrogateKeys::default(), &options).unwrap();
+        assert_eq!(normalized.documents.len(), kept, "{}", coupons);
+        assert_eq!(normalized.coupons.len(), 2 * kept, "{}", coupons);
+    }
+}
@@ -0,0 +1,193 @@
+// Integration tests of the PostgreSQL sink. They need a server, given as a connection
+// string in XMLPOC_TEST_PG, and are ignored unless asked for. A throwaway container does:
+//   docker run -d --rm -p 5432:5432 -e POSTGRES_PASSWORD=test postgres:16
+//   XMLPOC_TEST_PG="host=localhost user=postgres password=test" cargo test --test pgsink -- --ignored
+#![cfg(feature = "pg")]
+
+use tokio_postgres::{Client, NoTls};
+use xmlpoc::pgsink::PgSink;
+use xmlpoc::{Record, RecordSink};
+
+fn test_server() -> String {
+    std::env::var("XMLPOC_TEST_PG").expect("XMLPOC_TEST_PG holds the connection string")
+}
+
+async fn connect(conn_str: &str) -> Client {
+    let (client, connection) = tokio_postgres::connect(conn_str, NoTls).await.expect("connect");
+    tokio::spawn(connection);
+    client
+}
+
+// Staging and target tables with every Record column as text, named after the test.
+// `coupon_no_type` lets a test make rows fail the COPY.
+async fn create_tables(client: &Client, name: &str, coupon_no_type: &str) -> (String, String) {
+    let staging = format!("xmlpoc_test_{}_staging", name);
+    let target = format!("xmlpoc_test_{}_target", name);
+    let columns: Vec<String> = Record::COLUMNS
+        .iter()
+        .map(|c| match *c {
+            "coupon_no" => format!("{} {}", c, coupon_no_type),
+            c => format!("{} text", c),
+        })
+        .collect();
+
+    client
+        .batch_execute(&format!(
+            "DROP TABLE IF EXISTS {staging}, {target};
+             CREATE TABLE {staging} ({columns});
+             CREATE TABLE {target} ({columns}, PRIMARY KEY (ticket_no, coupon_no, document_status));",
+            staging = staging,
+            target = target,
+            columns = columns.join(", "),
+        ))
+        .await
+        .expect("create tables");
+    (staging, target)
+}
+
+async fn count(client: &Client, table: &str) -> i64 {
+    client.query_one(&format!("SELECT count(*) FROM {}", table), &[]).await.unwrap().get(0)
+}
+
+fn record(ticket_no: &str, coupon_no: &str, revenue: &str) -> Record {
+    Record {
+        ticket_no: ticket_no.to_string(),
+        coupon_no: coupon_no.to_string(),
+        document_status: "ISSUED".to_string(),
+        revenue: revenue.to_string(),
+        ..Default::default()
+    }
+}
+
+async fn load_file(sink: &mut PgSink, records: &[Record]) -> anyhow::Result<()> {
+    sink.begin_file().await?;
+    for rec in records {
+        sink.write_record(rec).await?;
+    }
+    sink.end_file().await
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_PG"]
+async fn upsert_replaces_rows_on_the_natural_key_and_empties_staging() {
+    let conn_str = test_server();
+    let client = connect(&conn_str).await;
+    let (staging, target) = create_tables(&client, "upsert", "text").await;
+
+    let mut sink = PgSink::connect(&conn_str, &staging, Some(&target), 1).await.unwrap();
+    load_file(&mut sink, &[record("1", "1", "10.00"), record("1", "2", "20.00")]).await.unwrap();
+    load_file(&mut sink, &[record("1", "2", "25.00")]).await.unwrap();
+    sink.finalize().await.unwrap();
+
+    assert_eq!(count(&client, &target).await, 2);
+    assert_eq!(count(&client, &staging).await, 0);
+    let revenue: String = client
+        .query_one(&format!("SELECT revenue FROM {} WHERE coupon_no = '2'", target), &[])
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(revenue, "25.00");
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_PG"]
+async fn latest_row_of_a_key_wins_within_a_file_and_rows_without_ticket_are_left_out() {
+    let conn_str = test_server();
+    let client = connect(&conn_str).await;
+    let (staging, target) = create_tables(&client, "latest", "text").await;
+
+    let resent = |revenue: &str, timestamp: &str| Record {
+        transaction_timestamp: timestamp.to_string(),
+        ..record("1", "1", revenue)
+    };
+    let mut sink = PgSink::connect(&conn_str, &staging, Some(&target), 10).await.unwrap();
+    load_file(
+        &mut sink,
+        &[
+            resent("10.00", "2025-11-20T08:00:00"),
+            resent("30.00", "2025-11-20T10:00:00"),
+            resent("20.00", "2025-11-20T09:00:00"),
+            record("", "1", "40.00"),
+            record("", "2", "50.00"),
+        ],
+    )
+    .await
+    .unwrap();
+
+    let rows: Vec<(String, String)> = client
+        .query(&format!("SELECT ticket_no, revenue FROM {}", target), &[])
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    assert_eq!(rows, [("1".to_string(), "30.00".to_string())]);
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_PG"]
+async fn failed_file_is_rolled_back_and_the_next_file_loads() {
+    let conn_str = test_server();
+    let client = connect(&conn_str).await;
+    let (staging, target) = create_tables(&client, "rollback", "integer").await;
+
+    // batch size 1: the second row's COPY fails, after the first row went to staging
+    let mut sink = PgSink::connect(&conn_str, &staging, Some(&target), 1).await.unwrap();
+    let failed = load_file(&mut sink, &[record("1", "1", "10.00"), record("1", "x", "20.00")]).await;
+    assert!(failed.is_err());
+    sink.abort_file().await.unwrap();
+
+    load_file(&mut sink, &[record("2", "1", "30.00")]).await.unwrap();
+    sink.finalize().await.unwrap();
+
+    let tickets: Vec<String> = client
+        .query(&format!("SELECT ticket_no FROM {}", target), &[])
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(tickets, ["2"]);
+    assert_eq!(count(&client, &staging).await, 0);
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_PG"]
+async fn failed_file_without_abort_is_rolled_back_by_the_next_begin() {
+    let conn_str = test_server();
+    let client = connect(&conn_str).await;
+    let (staging, target) = create_tables(&client, "reopen", "integer").await;
+
+    let mut sink = PgSink::connect(&conn_str, &staging, Some(&target), 1).await.unwrap();
+    assert!(load_file(&mut sink, &[record("1", "x", "10.00")]).await.is_err());
+    load_file(&mut sink, &[record("2", "1", "30.00")]).await.unwrap();
+
+    assert_eq!(count(&client, &target).await, 1);
+    assert_eq!(count(&client, &staging).await, 0);
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_PG"]
+async fn without_target_staging_holds_the_rows_of_the_current_run() {
+    let conn_str = test_server();
+    let client = connect(&conn_str).await;
+    let (staging, _) = create_tables(&client, "staging_only", "text").await;
+    client
+        .batch_execute(&format!("INSERT INTO {} (ticket_no) VALUES ('previous run')", staging))
+        .await
+        .unwrap();
+
+    let mut sink = PgSink::connect(&conn_str, &staging, None, 10).await.unwrap();
+    load_file(&mut sink, &[record("1", "1", "10.00")]).await.unwrap();
+    load_file(&mut sink, &[record("2", "1", "20.00")]).await.unwrap();
+    sink.finalize().await.unwrap();
+
+    let tickets: Vec<String> = client
+        .query(&format!("SELECT ticket_no FROM {} ORDER BY ticket_no", staging), &[])
+        .await
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(tickets, ["1", "2"]);
+}
@@ -0,0 +1,18 @@
+// PII protection of output columns.
+
+#![cfg(feature = "protect")]
+
+use xmlpoc::protect::{FieldProtector, Protection};
+
+#[test]
+fn key_columns_only_take_injective_protection() {
+    let key = ["ticket_no", "coupon_no"];
+    let protector = FieldProtector::new(&[("ticket_no", Protection::Mask { keep_last: 4 })], None, None).unwrap();
+    assert!(protector.check_key("DEDUPE_KEY", &key).is_err());
+    let protector = FieldProtector::new(&[("coupon_no", Protection::Drop)], None, None).unwrap();
+    assert!(protector.check_key("DEDUPE_KEY", &key).is_err());
+
+    let rules = [("ticket_no", Protection::Tokenize), ("pnr_no", Protection::Drop)];
+    let protector = FieldProtector::new(&rules, Some(b"secret".to_vec()), None).unwrap();
+    protector.check_key("DEDUPE_KEY", &key).unwrap();
+}
@@ -0,0 +1,27 @@
+// Reconciliation of the parse-time counts with the record output.
+
+use quick_xml::Reader;
+use xmlpoc::parse_xml;
+use xmlpoc::reconcile::{OutputCounts, reconcile};
+
+#[test]
+fn multi_coupon_transactions_reconcile_on_the_coupon_their_record_carries() {
+    let bytes = std::fs::read("tests/fixtures/sample.xml").unwrap();
+    let mut reader = Reader::from_reader(bytes.as_slice());
+    reader.trim_text(true);
+    let feed = parse_xml(&mut reader).unwrap();
+
+    // the issued ticket prorates 60 and 40 over its two coupons
+    assert_eq!(feed.stats.control.accounted_fare_amount, 100.0);
+    assert_eq!(feed.stats.control.kept_accounted_fare_amount, 40.0);
+
+    let output = OutputCounts {
+        rows_written: Some(feed.records.len() as u64),
+        fare_amount: Some(feed.records.iter().map(|r| r.cpn_far_fare_amount_accounting_currency.parse::<f64>().unwrap_or(0.0)).sum()),
+    };
+    assert_eq!(reconcile(&feed.stats.control, 0, &output), Vec::<String>::new());
+
+    // rows changed by deduplication or a SQL transform are not compared
+    let output = OutputCounts { rows_written: None, fare_amount: None };
+    assert_eq!(reconcile(&feed.stats.control, 0, &output), Vec::<String>::new());
+}
@@ -0,0 +1,59 @@
+// Daily summary totals per day and dimension values.
+
+use xmlpoc::config;
+use xmlpoc::summary::DailySummary;
+use xmlpoc::{LocalStorage, Record, RecordSink, Storage};
+
+fn record(issue_date: &str, carrier: &str, revenue: &str, tax: &str) -> Record {
+    Record {
+        issue_date: issue_date.to_string(),
+        validating_carrier: carrier.to_string(),
+        revenue: revenue.to_string(),
+        sum_cpn_txo_tax_amount_accounting_currency: tax.to_string(),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn records_are_counted_and_summed_per_day_and_dimensions() {
+    let dir = std::env::temp_dir().join(format!("xmlpoc_summary_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let storage = LocalStorage::new(&dir);
+
+    let mut summary = DailySummary::new(
+        "summary",
+        "output",
+        storage.clone(),
+        "20251125",
+        "issue_date",
+        &["validating_carrier"],
+        &[("revenue", "revenue"), ("tax", "sum_cpn_txo_tax_amount_accounting_currency")],
+    )
+    .unwrap();
+    for rec in [
+        record("2025-11-24T08:15:00Z", "LH", "100.10", "20.00"),
+        record("2025-11-24", "LH", "50.20", ""),
+        record("2025-11-24", "AF", "30", "5.5"),
+        record("2025-11-25 09:00:00", "LH", "10", "not a number"),
+    ] {
+        summary.write_record(&rec).await.unwrap();
+    }
+    summary.finalize().await.unwrap();
+
+    let prefix = format!("{}/20251125/summary", config::FOLDER_NAME);
+    let csv = String::from_utf8(storage.get("output", &format!("{}.csv", prefix)).await.unwrap()).unwrap();
+    assert_eq!(
+        csv.lines().collect::<Vec<_>>(),
+        [
+            "date,validating_carrier,record_count,revenue_total,tax_total",
+            "2025-11-24,AF,1,30,5.5",
+            "2025-11-24,LH,2,150.3,20",
+            "2025-11-25,LH,1,10,0",
+        ]
+    );
+
+    let json: serde_json::Value = serde_json::from_slice(&storage.get("output", &format!("{}.json", prefix)).await.unwrap()).unwrap();
+    assert_eq!(json["totals"], serde_json::json!({"record_count": 4, "revenue_total": 190.3, "tax_total": 25.5}));
+    assert_eq!(json["groups"][1]["record_count"], 2);
+    assert_eq!(json["unparsed_amounts"], serde_json::json!({"sum_cpn_txo_tax_amount_accounting_currency": 1}));
+}
@@ -0,0 +1,154 @@
+// Worker mode against an SQS stand-in such as ElasticMQ, given as an endpoint URL in
+// XMLPOC_TEST_SQS, and are ignored unless asked for. Objects come from a local store.
+//   docker run -d --rm -p 9324:9324 softwaremill/elasticmq-native
+//   XMLPOC_TEST_SQS=http://localhost:9324 cargo test --test worker -- --ignored
+#![cfg(feature = "aws")]
+
+use aws_sdk_sqs::config::{BehaviorVersion, Credentials, Region};
+use aws_sdk_sqs::types::QueueAttributeName;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use xmlpoc::config;
+use xmlpoc::pipeline::Worker;
+use xmlpoc::storage::LocalStorage;
+
+const INPUT_BUCKET: &str = "worker-test-input";
+
+// SQS client for the stand-in
+fn test_queue_client() -> aws_sdk_sqs::Client {
+    let endpoint = std::env::var("XMLPOC_TEST_SQS").expect("XMLPOC_TEST_SQS holds the endpoint URL");
+    let sqs_config = aws_sdk_sqs::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .endpoint_url(endpoint)
+        .region(Region::new("elasticmq"))
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .build();
+    aws_sdk_sqs::Client::from_conf(sqs_config)
+}
+
+// Queue whose messages reappear one second after a failed attempt
+async fn create_queue(client: &aws_sdk_sqs::Client, name: &str) -> String {
+    let queue = client
+        .create_queue()
+        .queue_name(format!("xmlpoc-{}-{}", name, std::process::id()))
+        .attributes(QueueAttributeName::VisibilityTimeout, "1")
+        .send()
+        .await
+        .expect("create queue");
+    queue.queue_url().unwrap().to_string()
+}
+
+// Empty store directory for one test
+fn store(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("xmlpoc_worker_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    root
+}
+
+fn put_input(root: &Path, key: &str, fixture: &str) {
+    let path = root.join(INPUT_BUCKET).join(key);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::copy(Path::new("tests/fixtures").join(fixture), path).unwrap();
+}
+
+fn created_event(keys: &[&str]) -> String {
+    let records: Vec<String> = keys
+        .iter()
+        .map(|key| {
+            format!(
+                r#"{{"eventName":"ObjectCreated:Put","s3":{{"bucket":{{"name":"{}"}},"object":{{"key":"{}"}}}}}}"#,
+                INPUT_BUCKET, key
+            )
+        })
+        .collect();
+    format!(r#"{{"Records":[{}]}}"#, records.join(","))
+}
+
+// First record chunk written for the object with file stem `stem`
+fn record_output(root: &Path, stem: &str) -> PathBuf {
+    let timestamp = chrono::Local::now().format(config::TIME_FORMAT).to_string();
+    root.join(config::OUTPUT_BUCKET)
+        .join(config::FOLDER_NAME)
+        .join(timestamp)
+        .join(format!("{}_{}_1{}", config::CSV_PREFIX, stem, config::EXTENSION))
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_SQS"]
+async fn processes_an_announced_object_and_deletes_the_message() {
+    let client = test_queue_client();
+    let queue_url = create_queue(&client, "success").await;
+    let root = store("success");
+    put_input(&root, "incoming/worker_success.xml", "sample.xml");
+    let storage = LocalStorage::new(&root);
+
+    client
+        .send_message()
+        .queue_url(&queue_url)
+        .message_body(created_event(&["incoming/worker_success.xml"]))
+        .send()
+        .await
+        .unwrap();
+
+    let mut worker = Worker::new(&storage, &client, &queue_url).await.unwrap().with_wait_time(1);
+    assert_eq!(worker.poll().await.unwrap(), 1);
+    assert!(record_output(&root, "worker_success").exists());
+
+    // deleted, so it does not come back after the visibility timeout
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(worker.poll().await.unwrap(), 0);
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_SQS"]
+async fn redelivered_message_skips_the_objects_already_done() {
+    let client = test_queue_client();
+    let queue_url = create_queue(&client, "partial").await;
+    let root = store("partial");
+    put_input(&root, "incoming/worker_first.xml", "sample.xml");
+    let storage = LocalStorage::new(&root);
+
+    // the second object is missing, so the message fails after the first one is done
+    client
+        .send_message()
+        .queue_url(&queue_url)
+        .message_body(created_event(&["incoming/worker_first.xml", "incoming/worker_late.xml"]))
+        .send()
+        .await
+        .unwrap();
+
+    let mut worker = Worker::new(&storage, &client, &queue_url).await.unwrap().with_wait_time(1);
+    assert_eq!(worker.poll().await.unwrap(), 0);
+    let first_output = record_output(&root, "worker_first");
+    let written = std::fs::metadata(&first_output).unwrap().modified().unwrap();
+
+    put_input(&root, "incoming/worker_late.xml", "sample_links.xml");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(worker.poll().await.unwrap(), 1);
+
+    assert!(record_output(&root, "worker_late").exists());
+    assert_eq!(std::fs::metadata(&first_output).unwrap().modified().unwrap(), written);
+}
+
+#[tokio::test]
+#[ignore = "needs XMLPOC_TEST_SQS"]
+async fn quarantined_copies_are_not_processed_again() {
+    let client = test_queue_client();
+    let queue_url = create_queue(&client, "quarantine").await;
+    let root = store("quarantine");
+    let key = format!("{}worker_quarantined.xml", config::QUARANTINE_PREFIX);
+    put_input(&root, &key, "sample.xml");
+    let storage = LocalStorage::new(&root);
+
+    client
+        .send_message()
+        .queue_url(&queue_url)
+        .message_body(created_event(&[&key]))
+        .send()
+        .await
+        .unwrap();
+
+    let mut worker = Worker::new(&storage, &client, &queue_url).await.unwrap().with_wait_time(1);
+    assert_eq!(worker.poll().await.unwrap(), 1);
+    assert!(!record_output(&root, "worker_quarantined").exists());
+}